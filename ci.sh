@@ -20,6 +20,12 @@ cargo build --target thumbv6m-none-eabi --features 'log'
 cargo build --target thumbv6m-none-eabi --features 'defmt'
 cargo build --target thumbv6m-none-eabi --features 'log,firmware-logs'
 cargo build --target thumbv6m-none-eabi --features 'defmt,firmware-logs'
+cargo build --target thumbv6m-none-eabi --features 'log,event-queue-depth-16'
+
+# host build and tests
+#=====================================
+
+cargo test -p cyw43 --features 'log,sim'
 
 (cd cyw43-pio; cargo build --target thumbv6m-none-eabi --features '')
 (cd cyw43-pio; cargo build --target thumbv6m-none-eabi --features 'overclock')
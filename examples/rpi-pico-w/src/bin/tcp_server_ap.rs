@@ -60,13 +60,15 @@ async fn main(spawner: Spawner) {
     let spi = PioSpi::new(&mut pio.common, pio.sm0, pio.irq0, cs, p.PIN_24, p.PIN_29, p.DMA_CH0);
 
     let state = singleton!(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+    let (net_device, mut control, runner) = unwrap!(cyw43::new(state, pwr, spi, fw).await);
     unwrap!(spawner.spawn(wifi_task(runner)));
 
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
+    unwrap!(control.init(clm, cyw43::countries::WORLD_WIDE_XX).await);
+    unwrap!(
+        control
+            .set_power_management(cyw43::PowerManagementMode::PowerSave)
+            .await
+    );
 
     // Use a link-local address for communication without DHCP server
     let config = Config::Static(embassy_net::StaticConfig {
@@ -89,7 +91,7 @@ async fn main(spawner: Spawner) {
     unwrap!(spawner.spawn(net_task(stack)));
 
     //control.start_ap_open("cyw43", 5).await;
-    control.start_ap_wpa2("cyw43", "password", 5).await;
+    unwrap!(control.start_ap_wpa2("cyw43", "password", 5).await);
 
     // And now we can use it!
 
@@ -101,7 +103,7 @@ async fn main(spawner: Spawner) {
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(embassy_net::SmolDuration::from_secs(10)));
 
-        control.gpio_set(0, false).await;
+        unwrap!(control.gpio_set(0, false).await);
         info!("Listening on TCP:1234...");
         if let Err(e) = socket.accept(1234).await {
             warn!("accept error: {:?}", e);
@@ -109,7 +111,7 @@ async fn main(spawner: Spawner) {
         }
 
         info!("Received connection from {:?}", socket.remote_endpoint());
-        control.gpio_set(0, true).await;
+        unwrap!(control.gpio_set(0, true).await);
 
         loop {
             let n = match socket.read(&mut buf).await {
@@ -58,18 +58,27 @@ async fn main(spawner: Spawner) {
     let spi = PioSpi::new(&mut pio.common, pio.sm0, pio.irq0, cs, p.PIN_24, p.PIN_29, p.DMA_CH0);
 
     let state = singleton!(cyw43::State::new());
-    let (_net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+    let (_net_device, mut control, runner) = unwrap!(cyw43::new(state, pwr, spi, fw).await);
     unwrap!(spawner.spawn(wifi_task(runner)));
 
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
+    unwrap!(control.init(clm, cyw43::countries::WORLD_WIDE_XX).await);
+    unwrap!(
+        control
+            .set_power_management(cyw43::PowerManagementMode::PowerSave)
+            .await
+    );
 
-    let mut scanner = control.scan().await;
+    let mut scanner = unwrap!(control.scan().await);
     while let Some(bss) = scanner.next().await {
-        if let Ok(ssid_str) = str::from_utf8(&bss.ssid) {
-            info!("scanned {} == {:x}", ssid_str, bss.bssid);
+        if let Ok(ssid_str) = str::from_utf8(bss.ssid()) {
+            info!(
+                "scanned {} == {:x}, channel {}, rssi {} dBm, {:?}",
+                ssid_str,
+                bss.bssid,
+                bss.channel(),
+                bss.rssi(),
+                bss.security().join_auth()
+            );
         }
     }
 }
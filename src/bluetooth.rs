@@ -0,0 +1,509 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_1::digital::OutputPin;
+
+use crate::bus::{Bus, SpiBusCyw43};
+use crate::consts::*;
+use crate::fmt::Bytes;
+use crate::{Chip, InitError};
+
+/// Maximum size of an HCI packet payload, not counting the H4 packet type byte.
+pub const BT_HCI_MTU: usize = 1024;
+
+/// Size of the header the firmware puts in front of every HCI packet in the shared buffers:
+/// 3 bytes of little endian payload length, followed by the H4 packet type.
+const BT_HCI_HEADER_SIZE: usize = 4;
+
+/// HCI packet type, as used in the H4 (UART) transport framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum HciPacketType {
+    Command = 0x01,
+    AclData = 0x02,
+    SyncData = 0x03,
+    Event = 0x04,
+    IsoData = 0x05,
+}
+
+impl HciPacketType {
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0x01 => Some(Self::Command),
+            0x02 => Some(Self::AclData),
+            0x03 => Some(Self::SyncData),
+            0x04 => Some(Self::Event),
+            0x05 => Some(Self::IsoData),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned by [`BtDriver::write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BtError {
+    /// The payload is longer than [`BT_HCI_MTU`].
+    PacketTooLong,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct BtPacket {
+    kind: HciPacketType,
+    len: usize,
+    buf: [u8; BT_HCI_MTU],
+}
+
+impl BtPacket {
+    fn payload(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// State shared between the [`BtDriver`] and the runner.
+pub struct BtState {
+    /// Packets received from the controller, waiting for the host stack.
+    rx: Channel<NoopRawMutex, BtPacket, 2>,
+    /// Packets from the host stack, waiting to be sent to the controller.
+    pub(crate) tx: Channel<NoopRawMutex, BtPacket, 2>,
+    /// Signaled when the host stack takes a packet out of `rx`.
+    rx_space: Signal<NoopRawMutex, ()>,
+}
+
+impl BtState {
+    pub fn new() -> Self {
+        Self {
+            rx: Channel::new(),
+            tx: Channel::new(),
+            rx_space: Signal::new(),
+        }
+    }
+}
+
+/// HCI transport to the Bluetooth controller.
+///
+/// Packets are exchanged with their H4 packet type kept separate from the payload,
+/// so this can sit directly underneath any BLE host stack.
+pub struct BtDriver<'a> {
+    state: &'a BtState,
+}
+
+impl<'a> BtDriver<'a> {
+    pub(crate) fn new(state: &'a BtState) -> Self {
+        Self { state }
+    }
+
+    /// Wait for the next HCI packet from the controller.
+    ///
+    /// The payload is copied into `buf`, and its length is returned along with the packet type.
+    /// If `buf` is too small, the payload is truncated.
+    ///
+    /// Only two received packets are queued. While the queue is full, the runner leaves further
+    /// packets in the controller's buffer until `read` makes room.
+    pub async fn read(&mut self, buf: &mut [u8]) -> (HciPacketType, usize) {
+        let packet = self.state.rx.receive().await;
+        self.state.rx_space.signal(());
+        let payload = packet.payload();
+        if payload.len() > buf.len() {
+            warn!("HCI packet of len {} truncated to {}", payload.len(), buf.len());
+        }
+
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        (packet.kind, len)
+    }
+
+    /// Send an HCI packet to the controller.
+    ///
+    /// `payload` is the packet without the H4 packet type byte, which is given by `kind`.
+    /// Returns [`BtError::PacketTooLong`] if it is longer than [`BT_HCI_MTU`].
+    pub async fn write(&mut self, kind: HciPacketType, payload: &[u8]) -> Result<(), BtError> {
+        if payload.len() > BT_HCI_MTU {
+            return Err(BtError::PacketTooLong);
+        }
+
+        let mut packet = BtPacket {
+            kind,
+            len: payload.len(),
+            buf: [0; BT_HCI_MTU],
+        };
+        packet.buf[..payload.len()].copy_from_slice(payload);
+        self.state.tx.send(packet).await;
+        Ok(())
+    }
+}
+
+/// Work for the `Runner` on the Bluetooth side, returned by [`BtRunner::wait`].
+pub(crate) enum BtWork {
+    /// A packet from the host stack, to be sent to the controller.
+    Write(BtPacket),
+    /// The host stack made room in the rx queue, and packets are still waiting in the controller's buffer.
+    Read,
+}
+
+/// Bluetooth side of the `Runner`.
+///
+/// On the CYW43439 the Bluetooth core is not reached through SPI function 3: the firmware
+/// exchanges HCI packets with the host through a pair of ring buffers in WLAN RAM, which
+/// are accessed over the backplane, and signals new data through the SDIO core mailbox interrupt.
+pub(crate) struct BtRunner<'a> {
+    state: &'a BtState,
+
+    /// Base address of the Bluetooth core.
+    bt_base: u32,
+    sdiod_base: u32,
+    /// Base address of the shared ring buffers in WLAN RAM.
+    addr: u32,
+    h2b_write_pointer: u32,
+    b2h_read_pointer: u32,
+    /// Set when reading stopped because `rx` was full, with packets left in the buffer.
+    rx_pending: bool,
+}
+
+impl<'a> BtRunner<'a> {
+    pub(crate) fn new(state: &'a BtState) -> Self {
+        Self {
+            state,
+            bt_base: 0,
+            sdiod_base: 0,
+            addr: 0,
+            h2b_write_pointer: 0,
+            b2h_read_pointer: 0,
+            rx_pending: false,
+        }
+    }
+
+    /// Wait for a packet to send, or for room to read more packets if some were left behind.
+    pub(crate) async fn wait(&self) -> BtWork {
+        if !self.rx_pending {
+            return BtWork::Write(self.state.tx.receive().await);
+        }
+
+        match select(self.state.tx.receive(), self.state.rx_space.wait()).await {
+            Either::First(packet) => BtWork::Write(packet),
+            Either::Second(()) => BtWork::Read,
+        }
+    }
+
+    pub(crate) async fn init_bluetooth<PWR, SPI>(
+        &mut self,
+        bus: &mut Bus<PWR, SPI>,
+        chip: &Chip,
+        firmware: &[u8],
+    ) -> Result<(), InitError>
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        let Some(bt_base) = chip.bluetooth_base_address else {
+            warn!("bt: {} has no Bluetooth core on this bus", chip.name);
+            return Err(InitError::Bluetooth);
+        };
+        self.bt_base = bt_base;
+        self.sdiod_base = chip.sdiod_core_base_address;
+
+        debug!("bt: powering up");
+        bus.bp_write32(self.bt_base + BT2WLAN_PWRUP_ADDR, BT2WLAN_PWRUP_WAKE)
+            .await;
+        Timer::after(Duration::from_millis(2)).await;
+
+        debug!("bt: loading fw");
+        self.upload_firmware(bus, firmware).await?;
+
+        debug!("bt: waiting for fw ready...");
+        wait_ctrl_reg(bus, BTSDIO_REG_FW_RDY_BITMASK).await?;
+
+        self.addr = bus.bp_read32(WLAN_RAM_BASE_REG_ADDR).await;
+        debug!("bt: shared buffers at {:08x}", self.addr);
+        bus.bp_write32(self.addr + BTSDIO_OFFSET_HOST2BT_IN, 0).await;
+        bus.bp_write32(self.addr + BTSDIO_OFFSET_HOST2BT_OUT, 0).await;
+        bus.bp_write32(self.addr + BTSDIO_OFFSET_BT2HOST_IN, 0).await;
+        bus.bp_write32(self.addr + BTSDIO_OFFSET_BT2HOST_OUT, 0).await;
+
+        self.set_awake(bus, true).await;
+        debug!("bt: waiting for awake...");
+        wait_ctrl_reg(bus, BTSDIO_REG_BT_AWAKE_BITMASK).await?;
+
+        let val = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        bus.bp_write32(HOST_CTRL_REG_ADDR, val | BTSDIO_REG_SW_RDY_BITMASK)
+            .await;
+        self.toggle_intr(bus).await;
+
+        debug!("bt init done");
+        Ok(())
+    }
+
+    async fn upload_firmware<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>, firmware: &[u8]) -> Result<(), InitError>
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        // The firmware starts with a length-prefixed version string, followed by the number of records.
+        let Some(&version_len) = firmware.first() else {
+            warn!("bt: empty fw");
+            return Err(InitError::Bluetooth);
+        };
+        let version_len = version_len as usize;
+        let Some(version) = firmware.get(1..1 + version_len) else {
+            warn!("bt: fw truncated in version string");
+            return Err(InitError::Bluetooth);
+        };
+        debug!("bt: fw version {:02x}", Bytes(version));
+        let mut records = firmware.get(1 + version_len + 1..).unwrap_or(&[]);
+
+        let mut base_addr = 0;
+        while records.len() >= 4 {
+            let len = records[0] as usize;
+            let addr = u16::from_be_bytes([records[1], records[2]]) as u32;
+            let kind = records[3];
+            let Some(data) = records.get(4..4 + len) else {
+                warn!("bt: fw record truncated");
+                return Err(InitError::Bluetooth);
+            };
+            records = &records[4 + len..];
+
+            match (kind, data) {
+                (BTFW_HEX_LINE_TYPE_DATA, _) => {
+                    write_unaligned(bus, self.bt_base + base_addr + addr, data).await;
+                }
+                (BTFW_HEX_LINE_TYPE_EXTENDED_SEGMENT_ADDRESS, &[a, b, ..]) => {
+                    base_addr = (u16::from_be_bytes([a, b]) as u32) << 4;
+                }
+                (BTFW_HEX_LINE_TYPE_EXTENDED_ADDRESS, &[a, b, ..]) => {
+                    base_addr = (u16::from_be_bytes([a, b]) as u32) << 16;
+                }
+                (BTFW_HEX_LINE_TYPE_ABSOLUTE_32BIT_ADDRESS, &[a, b, c, d, ..]) => {
+                    base_addr = u32::from_be_bytes([a, b, c, d]);
+                }
+                (BTFW_HEX_LINE_TYPE_END_OF_DATA, _) => return Ok(()),
+                _ => {
+                    warn!("bt: bad fw record, type {} len {}", kind, len);
+                    return Err(InitError::Bluetooth);
+                }
+            }
+        }
+
+        warn!("bt: fw has no end of data record");
+        Err(InitError::Bluetooth)
+    }
+
+    async fn set_awake<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>, awake: bool)
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        let val = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        let val = if awake {
+            val | BTSDIO_REG_WAKE_BT_BITMASK
+        } else {
+            val & !BTSDIO_REG_WAKE_BT_BITMASK
+        };
+        bus.bp_write32(HOST_CTRL_REG_ADDR, val).await;
+    }
+
+    /// Tell the firmware that we have touched the shared buffers.
+    async fn toggle_intr<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>)
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        let val = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        bus.bp_write32(HOST_CTRL_REG_ADDR, val ^ BTSDIO_REG_DATA_VALID_BITMASK)
+            .await;
+    }
+
+    /// Check the mailbox interrupt, and forward any packets the controller has sent.
+    pub(crate) async fn handle_irq<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>)
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        let addr = self.sdiod_base + SDIO_INT_STATUS;
+        let int_status = bus.bp_read32(addr).await;
+        if int_status & I_HMB_FC_CHANGE == 0 {
+            return;
+        }
+        bus.bp_write32(addr, int_status & I_HMB_FC_CHANGE).await;
+
+        self.read_packets(bus).await;
+    }
+
+    /// Forward packets from the BT-to-host ring buffer until it is empty, or `rx` is full.
+    ///
+    /// Packets that don't fit in `rx` are left in the buffer, and read once [`BtRunner::wait`]
+    /// returns [`BtWork::Read`], so the controller sees them as not consumed yet.
+    pub(crate) async fn read_packets<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>)
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        self.rx_pending = false;
+
+        let write_pointer = bus.bp_read32(self.addr + BTSDIO_OFFSET_BT2HOST_IN).await;
+        if write_pointer >= BTSDIO_FWBUF_SIZE || write_pointer % 4 != 0 {
+            warn!("bt: invalid write pointer {:08x}", write_pointer);
+            return;
+        }
+        if write_pointer == self.b2h_read_pointer {
+            return;
+        }
+
+        let read_pointer = self.b2h_read_pointer;
+        let buf_addr = self.addr + BTSDIO_OFFSET_HOST_READ_BUF;
+        while self.b2h_read_pointer != write_pointer {
+            let mut header = [0; BT_HCI_HEADER_SIZE];
+            bus.bp_read(buf_addr + self.b2h_read_pointer, &mut header).await;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+            let kind = header[3];
+
+            let payload_pointer = (self.b2h_read_pointer + BT_HCI_HEADER_SIZE as u32) % BTSDIO_FWBUF_SIZE;
+            let padded_len = (len + 3) & !3;
+            let next_pointer = (payload_pointer + padded_len as u32) % BTSDIO_FWBUF_SIZE;
+
+            if len > BT_HCI_MTU {
+                warn!("bt: rx packet too long, len={}", len);
+                self.b2h_read_pointer = next_pointer;
+                continue;
+            }
+            let Some(kind) = HciPacketType::from_u8(kind) else {
+                warn!("bt: rx unknown packet type {}", kind);
+                self.b2h_read_pointer = next_pointer;
+                continue;
+            };
+
+            let mut packet = BtPacket {
+                kind,
+                len,
+                buf: [0; BT_HCI_MTU],
+            };
+            read_ring(bus, buf_addr, payload_pointer, &mut packet.buf[..padded_len]).await;
+            trace!("bt rx {:?} {:02x}", kind, Bytes(&packet.payload()[..len.min(48)]));
+
+            if self.state.rx.try_send(packet).is_err() {
+                debug!("bt: rx queue full, waiting for the host to read");
+                self.rx_pending = true;
+                break;
+            }
+            self.b2h_read_pointer = next_pointer;
+        }
+
+        if self.b2h_read_pointer != read_pointer {
+            bus.bp_write32(self.addr + BTSDIO_OFFSET_BT2HOST_OUT, self.b2h_read_pointer)
+                .await;
+            self.toggle_intr(bus).await;
+        }
+    }
+
+    /// Write a packet from the host stack into the host-to-BT ring buffer.
+    pub(crate) async fn hci_write<PWR, SPI>(&mut self, bus: &mut Bus<PWR, SPI>, packet: &BtPacket)
+    where
+        PWR: OutputPin,
+        SPI: SpiBusCyw43,
+    {
+        let payload = packet.payload();
+        trace!(
+            "bt tx {:?} {:02x}",
+            packet.kind,
+            Bytes(&payload[..payload.len().min(48)])
+        );
+
+        let mut buf = [0; BT_HCI_HEADER_SIZE + BT_HCI_MTU];
+        let len_bytes = (payload.len() as u32).to_le_bytes();
+        buf[..3].copy_from_slice(&len_bytes[..3]);
+        buf[3] = packet.kind as u8;
+        buf[BT_HCI_HEADER_SIZE..][..payload.len()].copy_from_slice(payload);
+        let total_len = (BT_HCI_HEADER_SIZE + payload.len() + 3) & !3;
+
+        // Wait until the controller has consumed enough of the buffer for the packet to fit.
+        loop {
+            let read_pointer = bus.bp_read32(self.addr + BTSDIO_OFFSET_HOST2BT_OUT).await;
+            let free = read_pointer.wrapping_sub(self.h2b_write_pointer).wrapping_sub(4) % BTSDIO_FWBUF_SIZE;
+            if free as usize >= total_len {
+                break;
+            }
+            Timer::after(Duration::from_millis(1)).await;
+        }
+
+        let buf_addr = self.addr + BTSDIO_OFFSET_HOST_WRITE_BUF;
+        write_ring(bus, buf_addr, self.h2b_write_pointer, &buf[..total_len]).await;
+        self.h2b_write_pointer = (self.h2b_write_pointer + total_len as u32) % BTSDIO_FWBUF_SIZE;
+
+        bus.bp_write32(self.addr + BTSDIO_OFFSET_HOST2BT_IN, self.h2b_write_pointer)
+            .await;
+        self.toggle_intr(bus).await;
+    }
+}
+
+/// Wait until `mask` is set in the BT control register.
+async fn wait_ctrl_reg<PWR, SPI>(bus: &mut Bus<PWR, SPI>, mask: u32) -> Result<(), InitError>
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while bus.bp_read32(BT_CTRL_REG_ADDR).await & mask == 0 {
+        if Instant::now() > deadline {
+            warn!("bt: timed out waiting for ctrl reg {:08x}", mask);
+            return Err(InitError::Bluetooth);
+        }
+        Timer::after(Duration::from_millis(1)).await;
+    }
+    Ok(())
+}
+
+/// Read `data.len()` bytes from a ring buffer at `buf_addr`, starting at offset `pointer`.
+async fn read_ring<PWR, SPI>(bus: &mut Bus<PWR, SPI>, buf_addr: u32, pointer: u32, data: &mut [u8])
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let first_len = data.len().min((BTSDIO_FWBUF_SIZE - pointer) as usize);
+    let (first, second) = data.split_at_mut(first_len);
+    bus.bp_read(buf_addr + pointer, first).await;
+    if !second.is_empty() {
+        bus.bp_read(buf_addr, second).await;
+    }
+}
+
+/// Write `data` to a ring buffer at `buf_addr`, starting at offset `pointer`.
+async fn write_ring<PWR, SPI>(bus: &mut Bus<PWR, SPI>, buf_addr: u32, pointer: u32, data: &[u8])
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let first_len = data.len().min((BTSDIO_FWBUF_SIZE - pointer) as usize);
+    let (first, second) = data.split_at(first_len);
+    bus.bp_write(buf_addr + pointer, first).await;
+    if !second.is_empty() {
+        bus.bp_write(buf_addr, second).await;
+    }
+}
+
+/// Backplane writes must be word aligned, but firmware records are not,
+/// so merge the record with the existing memory contents around it.
+async fn write_unaligned<PWR, SPI>(bus: &mut Bus<PWR, SPI>, addr: u32, data: &[u8])
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let start = addr & !3;
+    let head = (addr - start) as usize;
+    let total_len = (head + data.len() + 3) & !3;
+
+    // Records are at most 255 bytes long.
+    let mut buf = [0; 264];
+    if (head + data.len()) % 4 != 0 {
+        let tail = bus.bp_read32(start + total_len as u32 - 4).await;
+        buf[total_len - 4..total_len].copy_from_slice(&tail.to_le_bytes());
+    }
+    if head != 0 {
+        let first = bus.bp_read32(start).await;
+        buf[..4].copy_from_slice(&first.to_le_bytes());
+    }
+    buf[head..][..data.len()].copy_from_slice(data);
+
+    bus.bp_write(start, &buf[..total_len]).await;
+}
@@ -1,10 +1,10 @@
 use embassy_futures::yield_now;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal_1::digital::OutputPin;
 use futures::FutureExt;
 
 use crate::consts::*;
-use crate::slice8_mut;
+use crate::{slice8_mut, InitError};
 
 /// Custom Spi Trait that _only_ supports the bus operation of the cyw43
 /// Implementors are expected to hold the CS pin low during an operation.
@@ -48,24 +48,33 @@ where
         }
     }
 
-    pub async fn init(&mut self) {
+    pub async fn init(&mut self) -> Result<(), InitError> {
         // Reset
         self.pwr.set_low().unwrap();
         Timer::after(Duration::from_millis(20)).await;
         self.pwr.set_high().unwrap();
         Timer::after(Duration::from_millis(250)).await;
 
+        let deadline = Instant::now() + Duration::from_millis(500);
         while self
             .read32_swapped(REG_BUS_TEST_RO)
             .inspect(|v| trace!("{:#x}", v))
             .await
             != FEEDBEAD
-        {}
+        {
+            if Instant::now() > deadline {
+                warn!("bus test: timed out waiting for {:#x}", FEEDBEAD);
+                return Err(InitError::BusTest);
+            }
+        }
 
         self.write32_swapped(REG_BUS_TEST_RW, TEST_PATTERN).await;
         let val = self.read32_swapped(REG_BUS_TEST_RW).await;
         trace!("{:#x}", val);
-        assert_eq!(val, TEST_PATTERN);
+        if val != TEST_PATTERN {
+            warn!("bus test: read back {:#x}, expected {:#x}", val, TEST_PATTERN);
+            return Err(InitError::BusTest);
+        }
 
         let val = self.read32_swapped(REG_BUS_CTRL).await;
         trace!("{:#010b}", (val & 0xff));
@@ -82,10 +91,24 @@ where
 
         let val = self.read32(FUNC_BUS, REG_BUS_TEST_RO).await;
         trace!("{:#x}", val);
-        assert_eq!(val, FEEDBEAD);
+        if val != FEEDBEAD {
+            warn!(
+                "bus test: read {:#x} after switching to 32-bit mode, expected {:#x}",
+                val, FEEDBEAD
+            );
+            return Err(InitError::BusTest);
+        }
         let val = self.read32(FUNC_BUS, REG_BUS_TEST_RW).await;
         trace!("{:#x}", val);
-        assert_eq!(val, TEST_PATTERN);
+        if val != TEST_PATTERN {
+            warn!(
+                "bus test: read back {:#x} after switching to 32-bit mode, expected {:#x}",
+                val, TEST_PATTERN
+            );
+            return Err(InitError::BusTest);
+        }
+
+        Ok(())
     }
 
     pub async fn wlan_read(&mut self, buf: &mut [u32], len_in_u8: u32) {
@@ -71,6 +71,40 @@ pub(crate) const AI_RESETCTRL_BIT_RESET: u8 = 1;
 
 pub(crate) const AI_RESETSTATUS_OFFSET: u32 = 0x804;
 
+// SDIO device core registers, relative to `sdiod_core_base_address`.
+pub(crate) const SDIO_INT_STATUS: u32 = 0x20;
+pub(crate) const SDIO_INT_HOST_MASK: u32 = 0x24;
+// "Host mailbox flow control change", raised by the BT core when it has touched the shared buffers.
+pub(crate) const I_HMB_FC_CHANGE: u32 = 1 << 5;
+
+// Bluetooth shared bus (BTSDIO) constants.
+pub(crate) const BT2WLAN_PWRUP_WAKE: u32 = 3;
+pub(crate) const BT2WLAN_PWRUP_ADDR: u32 = 0x640894;
+pub(crate) const BT_CTRL_REG_ADDR: u32 = 0x18000c7c;
+pub(crate) const HOST_CTRL_REG_ADDR: u32 = 0x18000d6c;
+pub(crate) const WLAN_RAM_BASE_REG_ADDR: u32 = 0x18000d68;
+
+pub(crate) const BTSDIO_REG_DATA_VALID_BITMASK: u32 = 1 << 1;
+pub(crate) const BTSDIO_REG_BT_AWAKE_BITMASK: u32 = 1 << 8;
+pub(crate) const BTSDIO_REG_WAKE_BT_BITMASK: u32 = 1 << 17;
+pub(crate) const BTSDIO_REG_SW_RDY_BITMASK: u32 = 1 << 24;
+pub(crate) const BTSDIO_REG_FW_RDY_BITMASK: u32 = 1 << 24;
+
+pub(crate) const BTSDIO_FWBUF_SIZE: u32 = 0x1000;
+pub(crate) const BTSDIO_OFFSET_HOST_WRITE_BUF: u32 = 0;
+pub(crate) const BTSDIO_OFFSET_HOST_READ_BUF: u32 = BTSDIO_FWBUF_SIZE;
+pub(crate) const BTSDIO_OFFSET_HOST2BT_IN: u32 = 0x00002000;
+pub(crate) const BTSDIO_OFFSET_HOST2BT_OUT: u32 = 0x00002004;
+pub(crate) const BTSDIO_OFFSET_BT2HOST_IN: u32 = 0x00002008;
+pub(crate) const BTSDIO_OFFSET_BT2HOST_OUT: u32 = 0x0000200C;
+
+// Record types in the Bluetooth patch firmware, which is an Intel HEX file packed into binary records.
+pub(crate) const BTFW_HEX_LINE_TYPE_DATA: u8 = 0x00;
+pub(crate) const BTFW_HEX_LINE_TYPE_END_OF_DATA: u8 = 0x01;
+pub(crate) const BTFW_HEX_LINE_TYPE_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+pub(crate) const BTFW_HEX_LINE_TYPE_EXTENDED_ADDRESS: u8 = 0x04;
+pub(crate) const BTFW_HEX_LINE_TYPE_ABSOLUTE_32BIT_ADDRESS: u8 = 0x05;
+
 pub(crate) const TEST_PATTERN: u32 = 0x12345678;
 pub(crate) const FEEDBEAD: u32 = 0xFEEDBEAD;
 
@@ -94,10 +128,22 @@ pub(crate) const IRQ_F3_INTR: u16 = 0x8000;
 
 pub(crate) const IOCTL_CMD_UP: u32 = 2;
 pub(crate) const IOCTL_CMD_DOWN: u32 = 3;
+pub(crate) const IOCTL_CMD_GET_RATE: u32 = 12;
+pub(crate) const IOCTL_CMD_SET_INFRA: u32 = 20;
+pub(crate) const IOCTL_CMD_SET_AUTH: u32 = 22;
+pub(crate) const IOCTL_CMD_GET_BSSID: u32 = 23;
+pub(crate) const IOCTL_CMD_GET_SSID: u32 = 25;
 pub(crate) const IOCTL_CMD_SET_SSID: u32 = 26;
+pub(crate) const IOCTL_CMD_GET_CHANNEL: u32 = 29;
 pub(crate) const IOCTL_CMD_SET_CHANNEL: u32 = 30;
+pub(crate) const IOCTL_CMD_DISASSOC: u32 = 52;
 pub(crate) const IOCTL_CMD_ANTDIV: u32 = 64;
 pub(crate) const IOCTL_CMD_SET_AP: u32 = 118;
+pub(crate) const IOCTL_CMD_GET_RSSI: u32 = 127;
+pub(crate) const IOCTL_CMD_GET_ASSOCLIST: u32 = 159;
+pub(crate) const IOCTL_CMD_SET_WSEC: u32 = 134;
+pub(crate) const IOCTL_CMD_GET_PHY_NOISE: u32 = 135;
+pub(crate) const IOCTL_CMD_SET_WPA_AUTH: u32 = 165;
 pub(crate) const IOCTL_CMD_SET_VAR: u32 = 263;
 pub(crate) const IOCTL_CMD_GET_VAR: u32 = 262;
 pub(crate) const IOCTL_CMD_SET_PASSPHRASE: u32 = 268;
@@ -106,6 +152,16 @@ pub(crate) const CHANNEL_TYPE_CONTROL: u8 = 0;
 pub(crate) const CHANNEL_TYPE_EVENT: u8 = 1;
 pub(crate) const CHANNEL_TYPE_DATA: u8 = 2;
 
+// Chanspec encoding (d11ac), the same on all supported chips.
+pub(crate) const CHANSPEC_BAND_MASK: u32 = 0xc000;
+pub(crate) const CHANSPEC_BAND_2G: u32 = 0x0000;
+pub(crate) const CHANSPEC_BAND_5G: u32 = 0xc000;
+pub(crate) const CHANSPEC_BW_10: u32 = 0x0800;
+pub(crate) const CHANSPEC_BW_20: u32 = 0x1000;
+pub(crate) const CHANSPEC_BW_40: u32 = 0x1800;
+pub(crate) const CHANSPEC_BW_MASK: u32 = 0x3800;
+pub(crate) const CHANSPEC_CTL_SB_NONE: u32 = 0x0000;
+
 // CYW_SPID command structure constants.
 pub(crate) const WRITE: bool = true;
 pub(crate) const READ: bool = false;
@@ -115,8 +171,33 @@ pub(crate) const FIXED_ADDR: bool = false;
 pub(crate) const AES_ENABLED: u32 = 0x0004;
 pub(crate) const WPA2_SECURITY: u32 = 0x00400000;
 
+pub(crate) const MAX_SSID_LEN: usize = 32;
 pub(crate) const MIN_PSK_LEN: usize = 8;
 pub(crate) const MAX_PSK_LEN: usize = 64;
+pub(crate) const MAX_SAE_PASSWORD_LEN: usize = 128;
+
+// Values for IOCTL_CMD_SET_AUTH
+pub(crate) const AUTH_OPEN: u32 = 0;
+pub(crate) const AUTH_SAE: u32 = 3;
+
+// Values for IOCTL_CMD_SET_WPA_AUTH
+pub(crate) const WPA_AUTH_DISABLED: u32 = 0x0000;
+pub(crate) const WPA2_AUTH_PSK: u32 = 0x0080;
+pub(crate) const WPA3_AUTH_SAE_PSK: u32 = 0x40000;
+
+// Values for the "mfp" iovar (802.11w management frame protection)
+pub(crate) const MFP_NONE: u32 = 0;
+pub(crate) const MFP_CAPABLE: u32 = 1;
+pub(crate) const MFP_REQUIRED: u32 = 2;
+
+// Flag in the "qtxpower" iovar to ignore the regulatory limits. Never set by us.
+pub(crate) const WL_TXPWR_OVERRIDE: u32 = 1 << 31;
+/// Largest power `qtxpower` accepts, in quarter dBm.
+pub(crate) const WL_TXPWR_MAX_QDBM: u32 = 0x7f;
+
+// Values for `ScanParams::action`
+pub(crate) const ESCAN_ACTION_START: u16 = 1;
+pub(crate) const ESCAN_ACTION_ABORT: u16 = 3;
 
 // Security type (authentication and encryption types are combined using bit mask)
 #[allow(non_camel_case_types)]
@@ -171,6 +252,123 @@ impl PartialEq<EStatus> for u32 {
     }
 }
 
+/// Error codes returned by the firmware for a failed IOCTL or iovar.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::FromPrimitive, num_enum::IntoPrimitive)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(i32)]
+pub enum Bcme {
+    /// generic error
+    ERROR = -1,
+    /// bad argument
+    BADARG = -2,
+    /// bad option
+    BADOPTION = -3,
+    /// not up
+    NOTUP = -4,
+    /// not down
+    NOTDOWN = -5,
+    /// not AP
+    NOTAP = -6,
+    /// not STA
+    NOTSTA = -7,
+    /// bad key index
+    BADKEYIDX = -8,
+    /// radio off
+    RADIOOFF = -9,
+    /// not band locked
+    NOTBANDLOCKED = -10,
+    /// no clock
+    NOCLK = -11,
+    /// bad rate set
+    BADRATESET = -12,
+    /// bad band
+    BADBAND = -13,
+    /// buffer too short
+    BUFTOOSHORT = -14,
+    /// buffer too long
+    BUFTOOLONG = -15,
+    /// busy
+    BUSY = -16,
+    /// not associated
+    NOTASSOCIATED = -17,
+    /// bad SSID len
+    BADSSIDLEN = -18,
+    /// out of range channel
+    OUTOFRANGECHAN = -19,
+    /// bad channel
+    BADCHAN = -20,
+    /// bad address
+    BADADDR = -21,
+    /// not enough resources
+    NORESOURCE = -22,
+    /// unsupported
+    UNSUPPORTED = -23,
+    /// bad length
+    BADLEN = -24,
+    /// not ready
+    NOTREADY = -25,
+    /// not permitted
+    EPERM = -26,
+    /// no memory
+    NOMEM = -27,
+    /// associated
+    ASSOCIATED = -28,
+    /// not in range
+    RANGE = -29,
+    /// not found (e.g. unknown iovar name)
+    NOTFOUND = -30,
+    /// WME not enabled
+    WME_NOT_ENABLED = -31,
+    /// TSPEC not found
+    TSPEC_NOTFOUND = -32,
+    /// ACM not supported
+    ACM_NOTSUPPORTED = -33,
+    /// not WME association
+    NOT_WME_ASSOCIATION = -34,
+    /// SDIO bus error
+    SDIO_ERROR = -35,
+    /// dongle not accessible
+    DONGLE_DOWN = -36,
+    /// incorrect version
+    VERSION = -37,
+    /// TX failure
+    TXFAIL = -38,
+    /// RX failure
+    RXFAIL = -39,
+    /// device not present
+    NODEVICE = -40,
+    /// NMODE disabled
+    NMODE_DISABLED = -41,
+    /// access to nonresident overlay
+    NONRESIDENT = -42,
+    /// scan rejected
+    SCANREJECT = -43,
+    /// usage error
+    USAGE_ERROR = -44,
+    /// IOCTL error
+    IOCTL_ERROR = -45,
+    /// serial port error
+    SERIAL_PORT_ERR = -46,
+    /// disabled in this build
+    DISABLED = -47,
+    /// decryption error
+    DECERR = -48,
+    /// encryption error
+    ENCERR = -49,
+    /// integrity/MIC error
+    MICERR = -50,
+    /// replay
+    REPLAY = -51,
+    /// IE not found
+    IE_NOTFOUND = -52,
+    /// error code not known to this driver, with the raw code
+    ///
+    /// The discriminant is only there to keep it from colliding with the ones above.
+    #[num_enum(catch_all)]
+    Unknown(i32) = i32::MAX,
+}
+
 #[allow(dead_code)]
 pub(crate) struct FormatStatus(pub u32);
 
@@ -316,3 +514,20 @@ impl core::fmt::Display for FormatInterrupt {
         core::fmt::Debug::fmt(self, f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcme_codes() {
+        assert_eq!(Bcme::from(-2), Bcme::BADARG);
+        assert_eq!(i32::from(Bcme::BADARG), -2);
+        assert_eq!(Bcme::from(-30), Bcme::NOTFOUND);
+
+        // Codes the driver doesn't know keep their value both ways.
+        assert_eq!(Bcme::from(-1000), Bcme::Unknown(-1000));
+        assert_eq!(i32::from(Bcme::Unknown(-1000)), -1000);
+        assert_eq!(Bcme::from(1), Bcme::Unknown(1));
+    }
+}
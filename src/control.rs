@@ -1,38 +1,416 @@
-use core::cmp::{max, min};
+use core::cmp::min;
 
 use ch::driver::LinkState;
 use embassy_net_driver_channel as ch;
-use embassy_time::{Duration, Timer};
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{with_timeout, Duration, Timer};
 
 pub use crate::bus::SpiBusCyw43;
 use crate::consts::*;
-use crate::events::{Event, EventSubscriber, Events};
+use crate::countries::Country;
+use crate::events::{DisconnectReason, Event, EventStats, EventStream, EventSubscriber, Events};
 use crate::fmt::Bytes;
-use crate::ioctl::{IoctlState, IoctlType};
+use crate::ioctl::{IoctlState, IoctlType, MAX_IOCTL_LEN};
+use crate::scan::ScanResult;
 use crate::structs::*;
-use crate::{countries, events, PowerManagementMode};
+use crate::{events, PowerManagementMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The firmware rejected an IOCTL or iovar.
+    Ioctl(Bcme),
+    /// Loading the CLM blob failed, with the given `clmload_status`.
+    ClmLoadFailed { status: u32 },
+    /// The passphrase length is not valid for the requested authentication mode.
+    InvalidPassphrase,
+    /// The SSID is longer than 32 bytes.
+    InvalidSsid,
+    /// The operation did not complete in time.
+    Timeout,
+    /// Joining the network failed. `status` is the `EStatus` reported with the `SET_SSID` event.
+    JoinFailed { status: u32 },
+    /// The SSID is too long, or too many channels were given in [`ScanOptions`].
+    InvalidScanOptions,
+    /// A scan is already running. Wait for it to complete, or drop its [`Scanner`] to abort it.
+    ScanInProgress,
+    /// All event subscriber slots are in use.
+    TooManySubscribers,
+    /// The firmware returned less data than expected for an IOCTL or iovar.
+    ShortResponse,
+    /// The buffer for an IOCTL or iovar is longer than `max`, [`MAX_IOCTL_LEN`]. Nothing was sent.
+    BufferTooLong { max: usize },
+    /// The transmit power is above the maximum of 31dBm.
+    InvalidTxPower,
+    /// The firmware reports counters in a `wl_cnt_t` layout this driver doesn't know.
+    UnsupportedCounters { version: u16 },
+}
+
+impl From<Bcme> for Error {
+    fn from(error: Bcme) -> Self {
+        Self::Ioctl(error)
+    }
+}
+
+/// Authentication mode used to join a network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoinAuth {
+    /// Open network, no authentication.
+    Open,
+    /// WPA2-Personal (PSK).
+    Wpa2,
+    /// WPA3-Personal (SAE). Management frame protection is required.
+    Wpa3,
+    /// WPA2/WPA3 transition mode. SAE is used, and management frame protection is enabled if the AP supports it.
+    Wpa2Wpa3,
+}
+
+/// Options for [`Control::join`].
+///
+/// ```ignore
+/// let options = JoinOptions::new("passphrase")
+///     .auth(JoinAuth::Wpa2Wpa3)
+///     .bssid([0x00, 0x11, 0x22, 0x33, 0x44, 0x55])
+///     .channel(6)
+///     .timeout(Duration::from_secs(10));
+/// control.join("ssid", options).await?;
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct JoinOptions<'a> {
+    pub auth: JoinAuth,
+    pub passphrase: &'a str,
+    /// Only join the AP with this BSSID.
+    pub bssid: Option<[u8; 6]>,
+    /// Channel the AP is expected on. This skips scanning the other channels.
+    pub channel: Option<u8>,
+    /// Give up joining after this long.
+    pub timeout: Option<Duration>,
+}
+
+// Debug and defmt::Format are implemented by hand so the passphrase doesn't end up in logs.
+impl<'a> core::fmt::Debug for JoinOptions<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JoinOptions")
+            .field("auth", &self.auth)
+            .field("passphrase", &"<redacted>")
+            .field("bssid", &self.bssid)
+            .field("channel", &self.channel)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> defmt::Format for JoinOptions<'a> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "JoinOptions {{ auth: {}, passphrase: <redacted>, bssid: {:02x}, channel: {}, timeout_ms: {} }}",
+            self.auth,
+            self.bssid,
+            self.channel,
+            self.timeout.map(|t| t.as_millis()),
+        )
+    }
+}
+
+impl<'a> JoinOptions<'a> {
+    /// Options for a WPA2 network with the given passphrase.
+    pub fn new(passphrase: &'a str) -> Self {
+        Self {
+            auth: JoinAuth::Wpa2,
+            passphrase,
+            bssid: None,
+            channel: None,
+            timeout: None,
+        }
+    }
+
+    /// Options for an open network.
+    pub fn new_open() -> Self {
+        Self {
+            auth: JoinAuth::Open,
+            ..Self::new("")
+        }
+    }
+
+    pub fn auth(self, auth: JoinAuth) -> Self {
+        Self { auth, ..self }
+    }
+
+    pub fn bssid(self, bssid: [u8; 6]) -> Self {
+        Self {
+            bssid: Some(bssid),
+            ..self
+        }
+    }
+
+    pub fn channel(self, channel: u8) -> Self {
+        Self {
+            channel: Some(channel),
+            ..self
+        }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+}
+
+/// Scan type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanType {
+    /// Send probe requests on each channel.
+    Active,
+    /// Only listen for beacons.
+    Passive,
+}
+
+/// Options for [`Control::scan_with_options`].
+///
+/// The default is a passive scan of all channels, using the firmware default times.
+///
+/// ```ignore
+/// let options = ScanOptions::default()
+///     .scan_type(ScanType::Active)
+///     .ssid("ssid")
+///     .channels(&[1, 6, 11])
+///     .dwell_time(Duration::from_millis(40));
+/// let mut scanner = control.scan_with_options(&options).await?;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanOptions<'a> {
+    pub scan_type: ScanType,
+    /// Only report networks with this SSID. For active scans, probe for it directly,
+    /// which also finds hidden networks.
+    pub ssid: Option<&'a str>,
+    /// Only report the network with this BSSID.
+    pub bssid: Option<[u8; 6]>,
+    /// Channels to scan, at most 14. Empty scans all channels.
+    pub channels: &'a [u8],
+    /// Number of probe requests to send per channel, for active scans.
+    pub nprobes: Option<u16>,
+    /// Time spent on each channel.
+    pub dwell_time: Option<Duration>,
+    /// Time spent on the home channel between scanned channels, when joined to a network.
+    pub home_time: Option<Duration>,
+}
+
+impl<'a> Default for ScanOptions<'a> {
+    fn default() -> Self {
+        Self {
+            scan_type: ScanType::Passive,
+            ssid: None,
+            bssid: None,
+            channels: &[],
+            nprobes: None,
+            dwell_time: None,
+            home_time: None,
+        }
+    }
+}
+
+impl<'a> ScanOptions<'a> {
+    pub fn scan_type(self, scan_type: ScanType) -> Self {
+        Self { scan_type, ..self }
+    }
+
+    pub fn ssid(self, ssid: &'a str) -> Self {
+        Self {
+            ssid: Some(ssid),
+            ..self
+        }
+    }
+
+    pub fn bssid(self, bssid: [u8; 6]) -> Self {
+        Self {
+            bssid: Some(bssid),
+            ..self
+        }
+    }
+
+    pub fn channels(self, channels: &'a [u8]) -> Self {
+        Self { channels, ..self }
+    }
 
-#[derive(Debug)]
-pub struct Error {
-    pub status: u32,
+    pub fn nprobes(self, nprobes: u16) -> Self {
+        Self {
+            nprobes: Some(nprobes),
+            ..self
+        }
+    }
+
+    pub fn dwell_time(self, dwell_time: Duration) -> Self {
+        Self {
+            dwell_time: Some(dwell_time),
+            ..self
+        }
+    }
+
+    pub fn home_time(self, home_time: Duration) -> Self {
+        Self {
+            home_time: Some(home_time),
+            ..self
+        }
+    }
+
+    /// The `escan` parameters for starting a scan with these options.
+    fn scan_params(&self) -> Result<ScanParams, Error> {
+        const SCANTYPE_ACTIVE: u8 = 0;
+        const SCANTYPE_PASSIVE: u8 = 1;
+
+        let ssid = self.ssid.unwrap_or("");
+        if ssid.len() > MAX_SSID_LEN || self.channels.len() > SCAN_MAX_CHANNELS {
+            return Err(Error::InvalidScanOptions);
+        }
+
+        // `!0` means "use the firmware default".
+        let millis = |d: Option<Duration>| d.map(|d| d.as_millis() as u32).unwrap_or(!0);
+        let dwell_time = millis(self.dwell_time);
+
+        let mut scan_params = ScanParams {
+            ssid_len: ssid.len() as u32,
+            bssid: self.bssid.unwrap_or([0xff; 6]),
+            scan_type: SCANTYPE_PASSIVE,
+            nprobes: self.nprobes.map(|n| n as u32).unwrap_or(!0),
+            home_time: millis(self.home_time),
+            channel_num: self.channels.len() as u32,
+            ..ScanParams::new(ESCAN_ACTION_START)
+        };
+        scan_params.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+        match self.scan_type {
+            ScanType::Active => {
+                scan_params.scan_type = SCANTYPE_ACTIVE;
+                scan_params.active_time = dwell_time;
+            }
+            ScanType::Passive => scan_params.passive_time = dwell_time,
+        }
+        for (chanspec_out, &channel) in scan_params.channel_list.iter_mut().zip(self.channels) {
+            *chanspec_out = chanspec(channel);
+        }
+        Ok(scan_params)
+    }
 }
 
+/// Information about the network the station is joined to, returned by [`Control::link_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkInfo {
+    /// Received signal strength of the AP, in dBm.
+    pub rssi: i32,
+    /// Noise floor, in dBm.
+    pub noise: i32,
+    /// Current transmit rate, in kbps.
+    pub rate_kbps: u32,
+    pub channel: u8,
+    pub bssid: [u8; 6],
+    ssid_len: u8,
+    ssid: [u8; 32],
+    pub counters: LinkCounters,
+}
+
+impl LinkInfo {
+    pub fn ssid(&self) -> &[u8] {
+        &self.ssid[..self.ssid_len as usize]
+    }
+
+    /// Signal to noise ratio, in dB.
+    pub fn snr(&self) -> i32 {
+        self.rssi - self.noise
+    }
+}
+
+/// Packet counters from the `counters` iovar. These count since the chip was initialized and wrap around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkCounters {
+    pub tx_frames: u32,
+    pub tx_bytes: u32,
+    /// Frames retransmitted.
+    pub tx_retries: u32,
+    /// Frames that failed to transmit.
+    pub tx_errors: u32,
+    pub rx_frames: u32,
+    pub rx_bytes: u32,
+    pub rx_errors: u32,
+}
+
+impl LinkCounters {
+    /// `wl_cnt_t` versions with the legacy flat layout. Newer firmware uses `wl_cnt_info_t`
+    /// (version 30 and up), which holds the counters in XTLVs instead.
+    const VERSIONS: core::ops::RangeInclusive<u16> = 6..=11;
+
+    /// Buffer size for reading the `counters` iovar. The firmware fails with `BUFTOOSHORT` unless
+    /// the whole `wl_cnt_t` fits. Broadcom's `wl` utility reads it into a `WLC_IOCTL_MEDLEN`
+    /// buffer (`wlioctl_defs.h`), which holds `wl_cnt_ver_11_t`, the largest of the layouts in
+    /// `VERSIONS`.
+    const BUF_LEN: usize = 1536;
+
+    /// Parse the start of a legacy `wl_cnt_t`, which is the same in all its versions.
+    fn parse(buf: &[u8]) -> Result<Self, Error> {
+        let version = u16::from_le_bytes(buf.get(0..2).ok_or(Error::ShortResponse)?.try_into().unwrap());
+        if !Self::VERSIONS.contains(&version) {
+            return Err(Error::UnsupportedCounters { version });
+        }
+
+        let word = |i: usize| -> Result<u32, Error> {
+            // after the u16 version and length
+            let offset = 4 + i * 4;
+            let bytes = buf.get(offset..offset + 4).ok_or(Error::ShortResponse)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        Ok(Self {
+            tx_frames: word(0)?,
+            tx_bytes: word(1)?,
+            tx_retries: word(2)?,
+            tx_errors: word(3)?,
+            rx_frames: word(15)?,
+            rx_bytes: word(16)?,
+            rx_errors: word(17)?,
+        })
+    }
+}
+
+/// bsscfg (and interface) index of the AP in concurrent AP+STA mode.
+const AP_BSSCFG: u32 = 1;
+
 pub struct Control<'a> {
     state_ch: ch::StateRunner<'a>,
     events: &'a Events,
     ioctl_state: &'a IoctlState,
+    /// Link state of the second interface, when running concurrent AP+STA.
+    ap_state_ch: Option<ch::StateRunner<'a>>,
+    mac_addr: [u8; 6],
+    ap_active: bool,
 }
 
 impl<'a> Control<'a> {
-    pub(crate) fn new(state_ch: ch::StateRunner<'a>, event_sub: &'a Events, ioctl_state: &'a IoctlState) -> Self {
+    pub(crate) fn new(
+        state_ch: ch::StateRunner<'a>,
+        ap_state_ch: Option<ch::StateRunner<'a>>,
+        event_sub: &'a Events,
+        ioctl_state: &'a IoctlState,
+    ) -> Self {
         Self {
             state_ch,
+            ap_state_ch,
             events: event_sub,
             ioctl_state,
+            mac_addr: [0; 6],
+            ap_active: false,
         }
     }
 
-    pub async fn init(&mut self, clm: &[u8]) {
+    /// Load the CLM blob and configure the chip, using the regulatory domain of `country`.
+    ///
+    /// Use [`crate::countries::WORLD_WIDE_XX`] if the country is not known. It only allows channels 1-11.
+    pub async fn init(&mut self, clm: &[u8], country: Country) -> Result<(), Error> {
         const CHUNK_SIZE: usize = 1024;
 
         debug!("Downloading CLM...");
@@ -59,136 +437,160 @@ impl<'a> Control<'a> {
             buf[8..20].copy_from_slice(&header.to_bytes());
             buf[20..][..chunk.len()].copy_from_slice(&chunk);
             self.ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..8 + 12 + chunk.len()])
-                .await;
+                .await?;
         }
 
         // check clmload ok
-        assert_eq!(self.get_iovar_u32("clmload_status").await, 0);
+        let status = self.get_iovar_u32("clmload_status").await?;
+        if status != 0 {
+            warn!("CLM load failed with status={}", status);
+            return Err(Error::ClmLoadFailed { status });
+        }
 
         debug!("Configuring misc stuff...");
 
         // Disable tx gloming which transfers multiple packets in one request.
         // 'glom' is short for "conglomerate" which means "gather together into
         // a compact mass".
-        self.set_iovar_u32("bus:txglom", 0).await;
-        self.set_iovar_u32("apsta", 1).await;
+        self.set_iovar_u32("bus:txglom", 0).await?;
+        self.set_iovar_u32("apsta", 1).await?;
 
         // read MAC addr.
         let mut mac_addr = [0; 6];
-        assert_eq!(self.get_iovar("cur_etheraddr", &mut mac_addr).await, 6);
+        if self.get_iovar("cur_etheraddr", &mut mac_addr).await? != 6 {
+            return Err(Error::ShortResponse);
+        }
         debug!("mac addr: {:02x}", Bytes(&mac_addr));
 
-        let country = countries::WORLD_WIDE_XX;
-        let country_info = CountryInfo {
-            country_abbrev: [country.code[0], country.code[1], 0, 0],
-            country_code: [country.code[0], country.code[1], 0, 0],
-            rev: if country.rev == 0 { -1 } else { country.rev as _ },
-        };
-        self.set_iovar("country", &country_info.to_bytes()).await;
-
-        // set country takes some time, next ioctls fail if we don't wait.
-        Timer::after(Duration::from_millis(100)).await;
+        self.set_country(country).await?;
 
         // Set antenna to chip antenna
-        self.ioctl_set_u32(IOCTL_CMD_ANTDIV, 0, 0).await;
+        self.ioctl_set_u32(IOCTL_CMD_ANTDIV, 0, 0).await?;
 
-        self.set_iovar_u32("bus:txglom", 0).await;
+        self.set_iovar_u32("bus:txglom", 0).await?;
         Timer::after(Duration::from_millis(100)).await;
         //self.set_iovar_u32("apsta", 1).await; // this crashes, also we already did it before...??
         //Timer::after(Duration::from_millis(100)).await;
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
+        self.set_iovar_u32("ampdu_ba_wsize", 8).await?;
         Timer::after(Duration::from_millis(100)).await;
-        self.set_iovar_u32("ampdu_mpdu", 4).await;
+        self.set_iovar_u32("ampdu_mpdu", 4).await?;
         Timer::after(Duration::from_millis(100)).await;
         //self.set_iovar_u32("ampdu_rx_factor", 0).await; // this crashes
 
         //Timer::after(Duration::from_millis(100)).await;
 
-        // evts
-        let mut evts = EventMask {
-            iface: 0,
-            events: [0xFF; 24],
-        };
-
-        // Disable spammy uninteresting events.
-        evts.unset(Event::RADIO);
-        evts.unset(Event::IF);
-        evts.unset(Event::PROBREQ_MSG);
-        evts.unset(Event::PROBREQ_MSG_RX);
-        evts.unset(Event::PROBRESP_MSG);
-        evts.unset(Event::PROBRESP_MSG);
-        evts.unset(Event::ROAM);
-
-        self.set_iovar("bsscfg:event_msgs", &evts.to_bytes()).await;
+        // Enable all events except spammy uninteresting ones, unless they are subscribed to.
+        let evts = self.events.mask.firmware_mask();
+        self.set_iovar("bsscfg:event_msgs", &evts.to_bytes()).await?;
 
         Timer::after(Duration::from_millis(100)).await;
 
         // set wifi up
-        self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await;
+        self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await?;
 
         Timer::after(Duration::from_millis(100)).await;
 
-        self.ioctl_set_u32(110, 0, 1).await; // SET_GMODE = auto
-        self.ioctl_set_u32(142, 0, 0).await; // SET_BAND = any
+        self.ioctl_set_u32(110, 0, 1).await?; // SET_GMODE = auto
+        self.ioctl_set_u32(142, 0, 0).await?; // SET_BAND = any
 
         Timer::after(Duration::from_millis(100)).await;
 
         self.state_ch.set_ethernet_address(mac_addr);
+        self.mac_addr = mac_addr;
 
         debug!("INIT DONE");
+        Ok(())
     }
 
-    pub async fn set_power_management(&mut self, mode: PowerManagementMode) {
+    pub async fn set_power_management(&mut self, mode: PowerManagementMode) -> Result<(), Error> {
         // power save mode
         let mode_num = mode.mode();
         if mode_num == 2 {
-            self.set_iovar_u32("pm2_sleep_ret", mode.sleep_ret_ms() as u32).await;
-            self.set_iovar_u32("bcn_li_bcn", mode.beacon_period() as u32).await;
-            self.set_iovar_u32("bcn_li_dtim", mode.dtim_period() as u32).await;
-            self.set_iovar_u32("assoc_listen", mode.assoc() as u32).await;
+            self.set_iovar_u32("pm2_sleep_ret", mode.sleep_ret_ms() as u32).await?;
+            self.set_iovar_u32("bcn_li_bcn", mode.beacon_period() as u32).await?;
+            self.set_iovar_u32("bcn_li_dtim", mode.dtim_period() as u32).await?;
+            self.set_iovar_u32("assoc_listen", mode.assoc() as u32).await?;
         }
-        self.ioctl_set_u32(86, 0, mode_num).await;
+        self.ioctl_set_u32(86, 0, mode_num).await
     }
 
     pub async fn join_open(&mut self, ssid: &str) -> Result<(), Error> {
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
+        self.join(ssid, JoinOptions::new_open()).await
+    }
 
-        self.ioctl_set_u32(134, 0, 0).await; // wsec = open
-        self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 0).await;
-        self.ioctl_set_u32(20, 0, 1).await; // set_infra = 1
-        self.ioctl_set_u32(22, 0, 0).await; // set_auth = open (0)
+    pub async fn join_wpa2(&mut self, ssid: &str, passphrase: &str) -> Result<(), Error> {
+        self.join(ssid, JoinOptions::new(passphrase)).await
+    }
 
-        let mut i = SsidInfo {
-            len: ssid.len() as _,
-            ssid: [0; 32],
+    /// Join a network.
+    ///
+    /// The passphrase in `options` is ignored for [`JoinAuth::Open`].
+    pub async fn join(&mut self, ssid: &str, options: JoinOptions<'_>) -> Result<(), Error> {
+        let JoinOptions { auth, passphrase, .. } = options;
+
+        if ssid.len() > MAX_SSID_LEN {
+            return Err(Error::InvalidSsid);
+        }
+
+        let psk_valid = (MIN_PSK_LEN..=MAX_PSK_LEN).contains(&passphrase.len());
+        let sae_valid = (1..=MAX_SAE_PASSWORD_LEN).contains(&passphrase.len());
+        let valid = match auth {
+            JoinAuth::Open => true,
+            JoinAuth::Wpa2 => psk_valid,
+            JoinAuth::Wpa3 => sae_valid,
+            JoinAuth::Wpa2Wpa3 => psk_valid && sae_valid,
         };
-        i.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+        if !valid {
+            return Err(Error::InvalidPassphrase);
+        }
 
-        self.wait_for_join(i).await
-    }
+        self.set_iovar_u32("ampdu_ba_wsize", 8).await?;
 
-    pub async fn join_wpa2(&mut self, ssid: &str, passphrase: &str) -> Result<(), Error> {
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
+        if auth == JoinAuth::Open {
+            self.ioctl_set_u32(IOCTL_CMD_SET_WSEC, 0, 0).await?; // wsec = open
+            self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 0).await?;
+        } else {
+            self.ioctl_set_u32(IOCTL_CMD_SET_WSEC, 0, AES_ENABLED).await?;
+            self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 1).await?;
+            self.set_iovar_u32x2("bsscfg:sup_wpa2_eapver", 0, 0xFFFF_FFFF).await?;
+            self.set_iovar_u32x2("bsscfg:sup_wpa_tmo", 0, 2500).await?;
 
-        self.ioctl_set_u32(134, 0, 4).await; // wsec = wpa2
-        self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 1).await;
-        self.set_iovar_u32x2("bsscfg:sup_wpa2_eapver", 0, 0xFFFF_FFFF).await;
-        self.set_iovar_u32x2("bsscfg:sup_wpa_tmo", 0, 2500).await;
+            Timer::after(Duration::from_millis(100)).await;
+        }
 
-        Timer::after(Duration::from_millis(100)).await;
+        if matches!(auth, JoinAuth::Wpa2 | JoinAuth::Wpa2Wpa3) {
+            let mut pfi = PassphraseInfo {
+                len: passphrase.len() as _,
+                flags: 1,
+                passphrase: [0; 64],
+            };
+            pfi.passphrase[..passphrase.len()].copy_from_slice(passphrase.as_bytes());
+            self.ioctl(IoctlType::Set, IOCTL_CMD_SET_PASSPHRASE, 0, &mut pfi.to_bytes())
+                .await?; // WLC_SET_WSEC_PMK
+        }
 
-        let mut pfi = PassphraseInfo {
-            len: passphrase.len() as _,
-            flags: 1,
-            passphrase: [0; 64],
-        };
-        pfi.passphrase[..passphrase.len()].copy_from_slice(passphrase.as_bytes());
-        self.ioctl(IoctlType::Set, IOCTL_CMD_SET_PASSPHRASE, 0, &mut pfi.to_bytes())
-            .await; // WLC_SET_WSEC_PMK
+        if matches!(auth, JoinAuth::Wpa3 | JoinAuth::Wpa2Wpa3) {
+            let mut pfi = SaePassphraseInfo {
+                len: passphrase.len() as _,
+                passphrase: [0; 128],
+            };
+            pfi.passphrase[..passphrase.len()].copy_from_slice(passphrase.as_bytes());
+            self.set_iovar_v::<256>("sae_password", &pfi.to_bytes()).await?;
+        }
+
+        self.ioctl_set_u32(IOCTL_CMD_SET_INFRA, 0, 1).await?; // set_infra = 1
 
-        self.ioctl_set_u32(20, 0, 1).await; // set_infra = 1
-        self.ioctl_set_u32(22, 0, 0).await; // set_auth = 0 (open)
-        self.ioctl_set_u32(165, 0, 0x80).await; // set_wpa_auth
+        let (auth_alg, mfp, wpa_auth) = match auth {
+            JoinAuth::Open => (AUTH_OPEN, MFP_NONE, WPA_AUTH_DISABLED),
+            JoinAuth::Wpa2 => (AUTH_OPEN, MFP_NONE, WPA2_AUTH_PSK),
+            JoinAuth::Wpa3 => (AUTH_SAE, MFP_REQUIRED, WPA3_AUTH_SAE_PSK),
+            JoinAuth::Wpa2Wpa3 => (AUTH_SAE, MFP_CAPABLE, WPA2_AUTH_PSK | WPA3_AUTH_SAE_PSK),
+        };
+        self.ioctl_set_u32(IOCTL_CMD_SET_AUTH, 0, auth_alg).await?;
+        if auth != JoinAuth::Open {
+            self.set_iovar_u32("mfp", mfp).await?;
+            self.ioctl_set_u32(IOCTL_CMD_SET_WPA_AUTH, 0, wpa_auth).await?;
+        }
 
         let mut i = SsidInfo {
             len: ssid.len() as _,
@@ -196,96 +598,240 @@ impl<'a> Control<'a> {
         };
         i.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
 
-        self.wait_for_join(i).await
+        self.wait_for_join(i, &options).await
     }
 
-    async fn wait_for_join(&mut self, i: SsidInfo) -> Result<(), Error> {
+    async fn wait_for_join(&mut self, i: SsidInfo, options: &JoinOptions<'_>) -> Result<(), Error> {
+        let mut subscriber = self.events.queue.subscriber().map_err(|_| Error::TooManySubscribers)?;
         self.events.mask.enable(&[Event::SET_SSID, Event::AUTH]);
-        let mut subscriber = self.events.queue.subscriber().unwrap();
         // the actual join operation starts here
         // we make sure to enable events before so we don't miss any
 
-        // set_ssid
-        self.ioctl(IoctlType::Set, IOCTL_CMD_SET_SSID, 0, &mut i.to_bytes())
-            .await;
+        if let Err(e) = self.start_join(i, options).await {
+            self.events.mask.disable(&[Event::SET_SSID, Event::AUTH]);
+            return Err(e);
+        }
 
         // to complete the join, we wait for a SET_SSID event
         // we also save the AUTH status for the user, it may be interesting
         let mut auth_status = 0;
-        let status = loop {
-            let msg = subscriber.next_message_pure().await;
-            if msg.header.event_type == Event::AUTH && msg.header.status != EStatus::SUCCESS {
-                auth_status = msg.header.status;
-            } else if msg.header.event_type == Event::SET_SSID {
-                // join operation ends with SET_SSID event
-                break msg.header.status;
+        let wait = async {
+            loop {
+                let msg = subscriber.next_message_pure().await;
+                if msg.header.event_type == Event::AUTH && msg.header.status != EStatus::SUCCESS {
+                    auth_status = msg.header.status;
+                } else if msg.header.event_type == Event::SET_SSID {
+                    // join operation ends with SET_SSID event
+                    break msg.header.status;
+                }
+            }
+        };
+        let status = match options.timeout {
+            Some(timeout) => with_timeout(timeout, wait).await,
+            None => Ok(wait.await),
+        };
+
+        self.events.mask.disable(&[Event::SET_SSID, Event::AUTH]);
+
+        let Ok(status) = status else {
+            warn!("JOIN timed out, auth={}", auth_status);
+            // Stop the firmware from trying any further.
+            if let Err(e) = self.ioctl(IoctlType::Set, IOCTL_CMD_DISASSOC, 0, &mut []).await {
+                warn!("failed to stop the join: {:?}", e);
             }
+            return Err(Error::Timeout);
         };
 
-        self.events.mask.disable_all();
         if status == EStatus::SUCCESS {
             // successful join
+            self.events.link.down_reason.set(None);
+            self.events.link.joined.set(true);
             self.state_ch.set_link_state(LinkState::Up);
             debug!("JOINED");
             Ok(())
         } else {
             warn!("JOIN failed with status={} auth={}", status, auth_status);
-            Err(Error { status })
+            Err(Error::JoinFailed { status })
+        }
+    }
+
+    /// Kick off the join, using the extended `join` iovar so the BSSID and channel can be given.
+    /// Falls back to `WLC_SET_SSID` on firmware that doesn't support it.
+    async fn start_join(&mut self, ssid: SsidInfo, options: &JoinOptions<'_>) -> Result<(), Error> {
+        let mut params = ExtJoinParams {
+            ssid,
+            scan_type: 0xFF, // firmware default
+            _pad: [0; 3],
+            nprobes: !0,
+            active_time: !0,
+            passive_time: !0,
+            home_time: !0,
+            bssid: options.bssid.unwrap_or([0xFF; 6]),
+            bssid_cnt: 0,
+            chanspec_num: 0,
+            chanspec_list: [0; 1],
+            _pad2: [0; 2],
+        };
+        if let Some(channel) = options.channel {
+            params.chanspec_num = 1;
+            params.chanspec_list[0] = chanspec(channel);
+        }
+
+        match self.set_iovar_v::<128>("join", &params.to_bytes()).await {
+            Err(Error::Ioctl(Bcme::UNSUPPORTED | Bcme::NOTFOUND)) => {
+                debug!("join iovar not supported, using WLC_SET_SSID");
+                if options.bssid.is_some() || options.channel.is_some() {
+                    warn!("BSSID and channel are ignored by WLC_SET_SSID");
+                }
+                self.ioctl(IoctlType::Set, IOCTL_CMD_SET_SSID, 0, &mut ssid.to_bytes())
+                    .await?;
+                Ok(())
+            }
+            res => res,
         }
     }
 
-    pub async fn gpio_set(&mut self, gpio_n: u8, gpio_en: bool) {
+    /// Leave the network we are joined to, and set the link down.
+    ///
+    /// Does nothing on the network side if we are not joined. If the firmware rejects the request,
+    /// the error is returned and the link state is left unchanged.
+    pub async fn leave(&mut self) -> Result<(), Error> {
+        let mut subscriber = self.events.queue.subscriber().map_err(|_| Error::TooManySubscribers)?;
+
+        // A link loss we cause ourselves is not a disconnect reason.
+        let was_joined = self.events.link.joined.replace(false);
+        let down_reason = self.events.link.down_reason.take();
+
+        self.events.mask.enable(&[Event::DISASSOC]);
+        let res = self.ioctl(IoctlType::Set, IOCTL_CMD_DISASSOC, 0, &mut []).await;
+        if res.is_ok() && was_joined {
+            let wait = async {
+                loop {
+                    let msg = subscriber.next_message_pure().await;
+                    if msg.header.event_type == Event::DISASSOC {
+                        break;
+                    }
+                }
+            };
+            // The firmware accepted the request, so the link is going down either way.
+            if with_timeout(Duration::from_secs(2), wait).await.is_err() {
+                warn!("no DISASSOC event after leaving");
+            }
+        }
+        self.events.mask.disable(&[Event::DISASSOC]);
+
+        if let Err(e) = res {
+            self.events.link.joined.set(was_joined);
+            self.events.link.down_reason.set(down_reason);
+            return Err(e);
+        }
+
+        self.state_ch.set_link_state(LinkState::Down);
+        debug!("LEFT");
+        Ok(())
+    }
+
+    /// Why the station link last went down, if it is currently down after having joined a network.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.events.link.down_reason.get()
+    }
+
+    pub async fn gpio_set(&mut self, gpio_n: u8, gpio_en: bool) -> Result<(), Error> {
         assert!(gpio_n < 3);
         self.set_iovar_u32x2("gpioout", 1 << gpio_n, if gpio_en { 1 << gpio_n } else { 0 })
             .await
     }
 
-    pub async fn start_ap_open(&mut self, ssid: &str, channel: u8) {
-        self.start_ap(ssid, "", Security::OPEN, channel).await;
+    pub async fn start_ap_open(&mut self, ssid: &str, channel: u8) -> Result<(), Error> {
+        self.start_ap(ssid, "", Security::OPEN, channel).await
     }
 
-    pub async fn start_ap_wpa2(&mut self, ssid: &str, passphrase: &str, channel: u8) {
-        self.start_ap(ssid, passphrase, Security::WPA2_AES_PSK, channel).await;
+    pub async fn start_ap_wpa2(&mut self, ssid: &str, passphrase: &str, channel: u8) -> Result<(), Error> {
+        self.start_ap(ssid, passphrase, Security::WPA2_AES_PSK, channel).await
     }
 
-    async fn start_ap(&mut self, ssid: &str, passphrase: &str, security: Security, channel: u8) {
+    /// Start the AP with the given config. If it is already running, it is stopped first,
+    /// so this can also be used to change the SSID, passphrase or channel.
+    ///
+    /// With the interfaces from [`crate::new_apsta`], the AP runs on the second interface (bsscfg 1),
+    /// and the station interface keeps working. Otherwise the chip is switched to AP-only mode.
+    async fn start_ap(&mut self, ssid: &str, passphrase: &str, security: Security, channel: u8) -> Result<(), Error> {
+        if ssid.len() > MAX_SSID_LEN {
+            return Err(Error::InvalidSsid);
+        }
         if security != Security::OPEN
             && (passphrase.as_bytes().len() < MIN_PSK_LEN || passphrase.as_bytes().len() > MAX_PSK_LEN)
         {
-            panic!("Passphrase is too short or too long");
+            return Err(Error::InvalidPassphrase);
         }
 
-        // Temporarily set wifi down
-        self.ioctl(IoctlType::Set, IOCTL_CMD_DOWN, 0, &mut []).await;
+        if self.ap_active {
+            self.stop_ap().await?;
+        }
 
-        // Turn off APSTA mode
-        self.set_iovar_u32("apsta", 0).await;
+        let concurrent = self.ap_state_ch.is_some();
+        // bsscfg index and interface index of the AP. These are the same for the interfaces we create.
+        let bsscfg = self.ap_iface();
 
-        // Set wifi up again
-        self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await;
+        if !concurrent {
+            // Taking the interface down drops any station connection. We cause the link loss
+            // ourselves, so it is not a disconnect reason.
+            self.events.link.joined.set(false);
+            self.events.link.down_reason.set(None);
+            self.state_ch.set_link_state(LinkState::Down);
+
+            // Temporarily set wifi down
+            self.ioctl(IoctlType::Set, IOCTL_CMD_DOWN, 0, &mut []).await?;
 
-        // Turn on AP mode
-        self.ioctl_set_u32(IOCTL_CMD_SET_AP, 0, 1).await;
+            // Turn off APSTA mode
+            self.set_iovar_u32("apsta", 0).await?;
+
+            // Set wifi up again
+            self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await?;
+
+            // Turn on AP mode
+            self.ioctl_set_u32(IOCTL_CMD_SET_AP, 0, 1).await?;
+        }
 
-        // Set SSID
+        // Set SSID. This also creates the bsscfg if it doesn't exist yet.
         let mut i = SsidInfoWithIndex {
-            index: 0,
+            index: bsscfg,
             ssid_info: SsidInfo {
                 len: ssid.as_bytes().len() as _,
                 ssid: [0; 32],
             },
         };
         i.ssid_info.ssid[..ssid.as_bytes().len()].copy_from_slice(ssid.as_bytes());
-        self.set_iovar("bsscfg:ssid", &i.to_bytes()).await;
+        self.set_iovar("bsscfg:ssid", &i.to_bytes()).await?;
+
+        if concurrent {
+            // The second interface needs its own MAC address. Use the locally administered
+            // variant of the primary one.
+            let mut mac_addr = self.mac_addr;
+            mac_addr[0] |= 0x02;
+            let mut buf = [0; 10];
+            buf[0..4].copy_from_slice(&bsscfg.to_le_bytes());
+            buf[4..10].copy_from_slice(&mac_addr);
+            self.set_iovar("bsscfg:cur_etheraddr", &buf).await?;
+            if let Some(ap_state_ch) = &self.ap_state_ch {
+                ap_state_ch.set_ethernet_address(mac_addr);
+            }
+        }
 
-        // Set channel number
-        self.ioctl_set_u32(IOCTL_CMD_SET_CHANNEL, 0, channel as u32).await;
+        // Set channel number. When the station is joined, the AP has to use its channel instead.
+        if concurrent && self.events.link.joined.get() {
+            debug!("station joined, AP uses its channel instead of {}", channel);
+        } else {
+            self.ioctl_set_u32(IOCTL_CMD_SET_CHANNEL, bsscfg, channel as u32)
+                .await?;
+        }
 
         // Set security
-        self.set_iovar_u32x2("bsscfg:wsec", 0, (security as u32) & 0xFF).await;
+        self.set_iovar_u32x2("bsscfg:wsec", bsscfg, (security as u32) & 0xFF)
+            .await?;
 
         if security != Security::OPEN {
-            self.set_iovar_u32x2("bsscfg:wpa_auth", 0, 0x0084).await; // wpa_auth = WPA2_AUTH_PSK | WPA_AUTH_PSK
+            self.set_iovar_u32x2("bsscfg:wpa_auth", bsscfg, 0x0084).await?; // wpa_auth = WPA2_AUTH_PSK | WPA_AUTH_PSK
 
             Timer::after(Duration::from_millis(100)).await;
 
@@ -296,40 +842,88 @@ impl<'a> Control<'a> {
                 passphrase: [0; 64],
             };
             pfi.passphrase[..passphrase.as_bytes().len()].copy_from_slice(passphrase.as_bytes());
-            self.ioctl(IoctlType::Set, IOCTL_CMD_SET_PASSPHRASE, 0, &mut pfi.to_bytes())
-                .await;
+            self.ioctl(IoctlType::Set, IOCTL_CMD_SET_PASSPHRASE, bsscfg, &mut pfi.to_bytes())
+                .await?;
         }
 
         // Change mutlicast rate from 1 Mbps to 11 Mbps
-        self.set_iovar_u32("2g_mrate", 11000000 / 500000).await;
+        self.set_iovar_u32("2g_mrate", 11000000 / 500000).await?;
 
         // Start AP
-        self.set_iovar_u32x2("bss", 0, 1).await; // bss = BSS_UP
+        self.set_iovar_u32x2("bss", bsscfg, 1).await?; // bss = BSS_UP
+        self.ap_active = true;
+
+        if let Some(ap_state_ch) = &self.ap_state_ch {
+            ap_state_ch.set_link_state(LinkState::Up);
+        }
+
+        Ok(())
     }
 
-    async fn set_iovar_u32x2(&mut self, name: &str, val1: u32, val2: u32) {
+    /// Stop the AP.
+    ///
+    /// Clients are disconnected. In AP-only mode, the chip goes back to station mode, configured
+    /// as after [`Control::init`], so networks can be joined again. In concurrent AP+STA mode,
+    /// the station interface is not affected.
+    pub async fn stop_ap(&mut self) -> Result<(), Error> {
+        if !self.ap_active {
+            return Ok(());
+        }
+
+        if let Some(ap_state_ch) = self.ap_state_ch {
+            self.set_iovar_u32x2("bss", AP_BSSCFG, 0).await?; // bss = BSS_DOWN
+            self.ap_active = false;
+            ap_state_ch.set_link_state(LinkState::Down);
+
+            debug!("AP stopped");
+            return Ok(());
+        }
+
+        // Stop AP
+        self.set_iovar_u32x2("bss", 0, 0).await?; // bss = BSS_DOWN
+        self.ap_active = false;
+
+        // Temporarily set wifi down
+        self.ioctl(IoctlType::Set, IOCTL_CMD_DOWN, 0, &mut []).await?;
+
+        // Turn off AP mode
+        self.ioctl_set_u32(IOCTL_CMD_SET_AP, 0, 0).await?;
+
+        // Turn APSTA mode back on, as `init` leaves it
+        self.set_iovar_u32("apsta", 1).await?;
+
+        // Set wifi up again
+        self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await?;
+
+        debug!("AP stopped");
+        Ok(())
+    }
+
+    async fn set_iovar_u32x2(&mut self, name: &str, val1: u32, val2: u32) -> Result<(), Error> {
         let mut buf = [0; 8];
         buf[0..4].copy_from_slice(&val1.to_le_bytes());
         buf[4..8].copy_from_slice(&val2.to_le_bytes());
         self.set_iovar(name, &buf).await
     }
 
-    async fn set_iovar_u32(&mut self, name: &str, val: u32) {
+    async fn set_iovar_u32(&mut self, name: &str, val: u32) -> Result<(), Error> {
         self.set_iovar(name, &val.to_le_bytes()).await
     }
 
-    async fn get_iovar_u32(&mut self, name: &str) -> u32 {
+    async fn get_iovar_u32(&mut self, name: &str) -> Result<u32, Error> {
         let mut buf = [0; 4];
-        let len = self.get_iovar(name, &mut buf).await;
-        assert_eq!(len, 4);
-        u32::from_le_bytes(buf)
+        let len = self.get_iovar(name, &mut buf).await?;
+        if len != 4 {
+            return Err(Error::ShortResponse);
+        }
+        Ok(u32::from_le_bytes(buf))
     }
 
-    async fn set_iovar(&mut self, name: &str, val: &[u8]) {
+    async fn set_iovar(&mut self, name: &str, val: &[u8]) -> Result<(), Error> {
         self.set_iovar_v::<64>(name, val).await
     }
 
-    async fn set_iovar_v<const BUFSIZE: usize>(&mut self, name: &str, val: &[u8]) {
+    async fn set_iovar_v<const BUFSIZE: usize>(&mut self, name: &str, val: &[u8]) -> Result<(), Error> {
         debug!("set {} = {:02x}", name, Bytes(val));
 
         let mut buf = [0; BUFSIZE];
@@ -339,33 +933,54 @@ impl<'a> Control<'a> {
 
         let total_len = name.len() + 1 + val.len();
         self.ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..total_len])
-            .await;
+            .await?;
+        Ok(())
     }
 
-    // TODO this is not really working, it always returns all zeros.
-    async fn get_iovar(&mut self, name: &str, res: &mut [u8]) -> usize {
+    /// Read the iovar `name` into `res`, returning the length of the response.
+    ///
+    /// The firmware needs the request and the response to fit in the same buffer, so if `res`
+    /// is longer than `name`, it is used for both and any size up to [`MAX_IOCTL_LEN`] works.
+    /// Longer buffers fail with [`Error::BufferTooLong`]. If the response is longer than `res`,
+    /// it is truncated.
+    /// Some iovars fail with `Bcme::BUFTOOSHORT` if `res` is shorter than their value.
+    pub async fn get_iovar(&mut self, name: &str, res: &mut [u8]) -> Result<usize, Error> {
         debug!("get {}", name);
 
+        if res.len() > name.len() {
+            res[..name.len()].copy_from_slice(name.as_bytes());
+            res[name.len()..].fill(0);
+            let res_len = self.ioctl(IoctlType::Get, IOCTL_CMD_GET_VAR, 0, res).await?;
+            return Ok(min(res.len(), res_len));
+        }
+
         let mut buf = [0; 64];
         buf[..name.len()].copy_from_slice(name.as_bytes());
         buf[name.len()] = 0;
 
-        let total_len = max(name.len() + 1, res.len());
+        let total_len = name.len() + 1;
         let res_len = self
             .ioctl(IoctlType::Get, IOCTL_CMD_GET_VAR, 0, &mut buf[..total_len])
-            .await;
+            .await?;
 
         let out_len = min(res.len(), res_len);
         res[..out_len].copy_from_slice(&buf[..out_len]);
-        out_len
+        Ok(out_len)
     }
 
-    async fn ioctl_set_u32(&mut self, cmd: u32, iface: u32, val: u32) {
+    async fn ioctl_get_u32(&mut self, cmd: u32, iface: u32) -> Result<u32, Error> {
+        let mut buf = [0; 4];
+        self.ioctl(IoctlType::Get, cmd, iface, &mut buf).await?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    async fn ioctl_set_u32(&mut self, cmd: u32, iface: u32, val: u32) -> Result<(), Error> {
         let mut buf = val.to_le_bytes();
-        self.ioctl(IoctlType::Set, cmd, iface, &mut buf).await;
+        self.ioctl(IoctlType::Set, cmd, iface, &mut buf).await?;
+        Ok(())
     }
 
-    async fn ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> usize {
+    async fn ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> Result<usize, Error> {
         struct CancelOnDrop<'a>(&'a IoctlState);
 
         impl CancelOnDrop<'_> {
@@ -380,14 +995,184 @@ impl<'a> Control<'a> {
             }
         }
 
+        if buf.len() > MAX_IOCTL_LEN {
+            return Err(Error::BufferTooLong { max: MAX_IOCTL_LEN });
+        }
+
         let ioctl = CancelOnDrop(self.ioctl_state);
 
-        ioctl.0.do_ioctl(kind, cmd, iface, buf).await;
-        let resp_len = ioctl.0.wait_complete().await;
+        let resp_len = ioctl.0.do_ioctl(kind, cmd, iface, buf).await;
 
         ioctl.defuse();
 
-        resp_len
+        Ok(resp_len?)
+    }
+
+    /// Get the signal strength, rate, channel and counters of the network the station is joined to.
+    ///
+    /// Returns `Error::Ioctl(Bcme::NOTASSOCIATED)` if not joined, and
+    /// [`Error::UnsupportedCounters`] if the firmware's counters layout isn't supported.
+    pub async fn link_info(&mut self) -> Result<LinkInfo, Error> {
+        let mut bssid = [0; 6];
+        self.ioctl(IoctlType::Get, IOCTL_CMD_GET_BSSID, 0, &mut bssid).await?;
+
+        let mut buf = [0; SsidInfo::SIZE];
+        self.ioctl(IoctlType::Get, IOCTL_CMD_GET_SSID, 0, &mut buf).await?;
+        let ssid_len = u32::from_le_bytes(buf[0..4].try_into().unwrap()).min(32) as u8;
+        let mut ssid = [0; 32];
+        ssid.copy_from_slice(&buf[4..36]);
+
+        let rssi = self.ioctl_get_u32(IOCTL_CMD_GET_RSSI, 0).await? as i32;
+        let noise = self.ioctl_get_u32(IOCTL_CMD_GET_PHY_NOISE, 0).await? as i32;
+        // in units of 500kbps
+        let rate_kbps = self.ioctl_get_u32(IOCTL_CMD_GET_RATE, 0).await? * 500;
+
+        // channel_info_t: hw_channel, target_channel, scan_channel
+        let mut buf = [0; 12];
+        self.ioctl(IoctlType::Get, IOCTL_CMD_GET_CHANNEL, 0, &mut buf).await?;
+        let channel = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as u8;
+
+        let mut buf = [0; LinkCounters::BUF_LEN];
+        let len = self.get_iovar("counters", &mut buf).await?;
+        let counters = LinkCounters::parse(&buf[..len])?;
+
+        Ok(LinkInfo {
+            rssi,
+            noise,
+            rate_kbps,
+            channel,
+            bssid,
+            ssid_len,
+            ssid,
+            counters,
+        })
+    }
+
+    /// Get the MAC addresses of the clients associated to the AP.
+    ///
+    /// Fills `clients`, and returns how many there are. If there are more than fit in `clients`,
+    /// only the first `clients.len()` are returned.
+    pub async fn ap_clients(&mut self, clients: &mut [[u8; 6]]) -> Result<usize, Error> {
+        const MAX_CLIENTS: usize = 16;
+
+        // struct maclist { uint count; struct ether_addr ea[]; }
+        let mut buf = [0; 4 + 6 * MAX_CLIENTS];
+        buf[0..4].copy_from_slice(&(MAX_CLIENTS as u32).to_le_bytes());
+        let iface = self.ap_iface();
+        self.ioctl(IoctlType::Get, IOCTL_CMD_GET_ASSOCLIST, iface, &mut buf)
+            .await?;
+
+        let count = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let count = count.min(MAX_CLIENTS).min(clients.len());
+        for (i, client) in clients[..count].iter_mut().enumerate() {
+            client.copy_from_slice(&buf[4 + 6 * i..][..6]);
+        }
+        Ok(count)
+    }
+
+    /// Get the RSSI of a client associated to the AP, in dBm.
+    pub async fn ap_client_rssi(&mut self, addr: [u8; 6]) -> Result<i32, Error> {
+        let scb_val = ScbVal {
+            val: 0,
+            addr,
+            _pad: [0; 2],
+        };
+        let mut buf = scb_val.to_bytes();
+        let iface = self.ap_iface();
+        self.ioctl(IoctlType::Get, IOCTL_CMD_GET_RSSI, iface, &mut buf).await?;
+        Ok(i32::from_le_bytes(buf[0..4].try_into().unwrap()))
+    }
+
+    /// Get a stream of clients joining and leaving the AP.
+    ///
+    /// The stream doesn't borrow `Control`, so it can be awaited in another task. It should be read
+    /// promptly, or dropped when no longer needed, see [`Control::event_stats`].
+    ///
+    /// Returns [`Error::TooManySubscribers`] if all event subscriber slots are in use.
+    pub fn ap_client_events(&self) -> Result<ApClientEvents<'a>, Error> {
+        let subscriber = self.events.queue.subscriber().map_err(|_| Error::TooManySubscribers)?;
+        self.events.mask.enable(AP_CLIENT_EVENTS);
+        Ok(ApClientEvents {
+            subscriber,
+            events: self.events,
+            iface: self.ap_iface() as u8,
+        })
+    }
+
+    /// Change the country, which sets the allowed channels and transmit power limits.
+    ///
+    /// This should be done while not joined to a network and with the AP stopped.
+    pub async fn set_country(&mut self, country: Country) -> Result<(), Error> {
+        debug!("set country {:?} rev {}", Bytes(&country.code), country.rev);
+
+        let country_info = CountryInfo {
+            country_abbrev: [country.code[0], country.code[1], 0, 0],
+            country_code: [country.code[0], country.code[1], 0, 0],
+            rev: if country.rev == 0 { -1 } else { country.rev as _ },
+        };
+        self.set_iovar("country", &country_info.to_bytes()).await?;
+
+        // set country takes some time, next ioctls fail if we don't wait.
+        Timer::after(Duration::from_millis(100)).await;
+        Ok(())
+    }
+
+    /// Limit the transmit power to `dbm`.
+    ///
+    /// The regulatory limits of the country set in the CLM always apply, so the actual power
+    /// may be lower than this.
+    ///
+    /// Returns [`Error::InvalidTxPower`] if `dbm` is above 31.
+    pub async fn set_tx_power(&mut self, dbm: u8) -> Result<(), Error> {
+        // qtxpower is in quarter dBm. WL_TXPWR_OVERRIDE is left clear, so the regulatory limits are honored.
+        let qdbm = dbm as u32 * 4;
+        if qdbm > WL_TXPWR_MAX_QDBM {
+            return Err(Error::InvalidTxPower);
+        }
+        self.set_iovar_u32("qtxpower", qdbm).await
+    }
+
+    /// Get the transmit power limit, in dBm.
+    pub async fn get_tx_power(&mut self) -> Result<u8, Error> {
+        let qdbm = self.get_iovar_u32("qtxpower").await? & !WL_TXPWR_OVERRIDE;
+        Ok((qdbm / 4) as u8)
+    }
+
+    /// Event delivery statistics, to tell if events were lost because subscribers didn't keep up.
+    ///
+    /// All event subscribers ([`Scanner`], [`EventStream`] and [`ApClientEvents`]) share one queue
+    /// of [`EVENT_QUEUE_DEPTH`](crate::EVENT_QUEUE_DEPTH) events. The `Runner` never waits for
+    /// subscribers, so when the queue is full the oldest event is dropped, for every subscriber.
+    /// Each subscriber counts the events it missed in its `lagged()`.
+    pub fn event_stats(&self) -> EventStats {
+        self.events.stats.snapshot()
+    }
+
+    /// Subscribe to raw firmware events.
+    ///
+    /// The given events are delivered until the returned stream is dropped. Events used internally
+    /// (for example by [`Control::join`] and [`Control::scan`]) stay enabled for as long as they
+    /// are needed, regardless of other subscriptions.
+    ///
+    /// Most events are always enabled in the firmware, and only filtered by the driver. Spammy ones
+    /// (`RADIO`, `IF`, `PROBREQ_MSG`, `PROBREQ_MSG_RX`, `PROBRESP_MSG` and `ROAM`) are only enabled
+    /// in the firmware while subscribed to. The `Runner` updates the firmware mask shortly after
+    /// subscribing, so such events occurring right away may be missed.
+    ///
+    /// The stream doesn't borrow `Control`, so it can be awaited in another task. It should be read
+    /// promptly, or dropped when no longer needed, see [`Control::event_stats`].
+    pub fn subscribe_events(&self, events: &[Event]) -> Result<EventStream<'a>, Error> {
+        let subscriber = self.events.queue.subscriber().map_err(|_| Error::TooManySubscribers)?;
+        Ok(EventStream::new(subscriber, self.events, self.ioctl_state, events))
+    }
+
+    /// Interface the AP runs on.
+    fn ap_iface(&self) -> u32 {
+        if self.ap_state_ch.is_some() {
+            AP_BSSCFG
+        } else {
+            0
+        }
     }
 
     /// Start a wifi scan
@@ -395,63 +1180,394 @@ impl<'a> Control<'a> {
     /// Returns a `Stream` of networks found by the device
     ///
     /// # Note
-    /// Device events are currently implemented using a bounded queue.
-    /// To not miss any events, you should make sure to always await the stream.
-    pub async fn scan(&mut self) -> Scanner<'_> {
-        const SCANTYPE_PASSIVE: u8 = 1;
+    /// Device events are delivered through a bounded queue, so results are dropped if the stream
+    /// isn't awaited promptly. Use [`Scanner::lagged`] or [`Control::event_stats`] to detect this.
+    pub async fn scan(&mut self) -> Result<Scanner<'_>, Error> {
+        self.scan_with_options(&ScanOptions::default()).await
+    }
 
-        let scan_params = ScanParams {
-            version: 1,
-            action: 1,
-            sync_id: 1,
-            ssid_len: 0,
-            ssid: [0; 32],
-            bssid: [0xff; 6],
-            bss_type: 2,
-            scan_type: SCANTYPE_PASSIVE,
-            nprobes: !0,
-            active_time: !0,
-            passive_time: !0,
-            home_time: !0,
-            channel_num: 0,
-            channel_list: [0; 1],
-        };
+    /// Start a wifi scan with the given options.
+    ///
+    /// See [`Control::scan`].
+    pub async fn scan_with_options(&mut self, options: &ScanOptions<'_>) -> Result<Scanner<'_>, Error> {
+        if self.events.scan_active.get() {
+            return Err(Error::ScanInProgress);
+        }
 
-        self.events.mask.enable(&[Event::ESCAN_RESULT]);
-        let subscriber = self.events.queue.subscriber().unwrap();
-        self.set_iovar_v::<256>("escan", &scan_params.to_bytes()).await;
+        let scan_params = options.scan_params()?;
+
+        /// Undoes the scan setup unless the escan iovar succeeds, including when the future is
+        /// dropped while waiting for it.
+        struct ResetOnDrop<'a>(&'a Events);
 
-        Scanner {
+        impl ResetOnDrop<'_> {
+            fn defuse(self) {
+                core::mem::forget(self);
+            }
+        }
+
+        impl Drop for ResetOnDrop<'_> {
+            fn drop(&mut self) {
+                self.0.scan_active.set(false);
+                self.0.mask.disable(&[Event::ESCAN_RESULT]);
+            }
+        }
+
+        let subscriber = self.events.queue.subscriber().map_err(|_| Error::TooManySubscribers)?;
+        self.events.mask.enable(&[Event::ESCAN_RESULT]);
+        // Set before starting the scan, the runner clears it when the final result comes in,
+        // which can happen before the iovar response is processed.
+        self.events.scan_active.set(true);
+        let reset = ResetOnDrop(self.events);
+        self.set_iovar_v::<256>("escan", &scan_params.to_bytes()).await?;
+        reset.defuse();
+
+        Ok(Scanner {
             subscriber,
             events: &self.events,
-        }
+            ioctl_state: &self.ioctl_state,
+            done: false,
+            lagged: 0,
+        })
     }
 }
 
+/// A running scan, returned by [`Control::scan`].
+///
+/// Dropping it before the scan completes aborts the scan.
 pub struct Scanner<'a> {
     subscriber: EventSubscriber<'a>,
     events: &'a Events,
+    ioctl_state: &'a IoctlState,
+    done: bool,
+    lagged: u64,
 }
 
 impl Scanner<'_> {
     /// wait for the next found network
-    pub async fn next(&mut self) -> Option<BssInfo> {
-        let event = self.subscriber.next_message_pure().await;
+    pub async fn next(&mut self) -> Option<ScanResult> {
+        if self.done {
+            return None;
+        }
+
+        let event = loop {
+            match self.subscriber.next_message().await {
+                WaitResult::Message(event) if event.header.event_type == Event::ESCAN_RESULT => break event,
+                WaitResult::Message(_) => {}
+                WaitResult::Lagged(n) => self.lagged += n,
+            }
+        };
         if event.header.status != EStatus::PARTIAL {
-            self.events.mask.disable_all();
+            self.done = true;
+            self.events.scan_active.set(false);
             return None;
         }
 
-        if let events::Payload::BssInfo(bss) = event.payload {
+        if let events::Payload::ScanResult(bss) = event.payload {
             Some(bss)
         } else {
             None
         }
     }
+
+    /// Number of events, possibly scan results, missed because the scanner wasn't awaited in time.
+    pub fn lagged(&self) -> u64 {
+        self.lagged
+    }
 }
 
 impl Drop for Scanner<'_> {
     fn drop(&mut self) {
-        self.events.mask.disable_all();
+        self.events.mask.disable(&[Event::ESCAN_RESULT]);
+        if self.events.scan_active.get() {
+            self.ioctl_state.request_scan_abort();
+        }
+    }
+}
+
+/// A client joining or leaving the AP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ApClientEvent {
+    /// A client associated.
+    Assoc { addr: [u8; 6] },
+    /// A client reassociated.
+    Reassoc { addr: [u8; 6] },
+    /// A client disassociated. `reason` is the 802.11 reason code.
+    Disassoc { addr: [u8; 6], reason: u16 },
+    /// A client deauthenticated. `reason` is the 802.11 reason code.
+    Deauth { addr: [u8; 6], reason: u16 },
+}
+
+impl ApClientEvent {
+    /// MAC address of the client.
+    pub fn addr(&self) -> [u8; 6] {
+        match *self {
+            Self::Assoc { addr } | Self::Reassoc { addr } => addr,
+            Self::Disassoc { addr, .. } | Self::Deauth { addr, .. } => addr,
+        }
+    }
+}
+
+const AP_CLIENT_EVENTS: &[Event] = &[
+    Event::ASSOC_IND,
+    Event::REASSOC_IND,
+    Event::DISASSOC_IND,
+    Event::DEAUTH_IND,
+];
+
+pub struct ApClientEvents<'a> {
+    subscriber: EventSubscriber<'a>,
+    events: &'a Events,
+    iface: u8,
+}
+
+impl ApClientEvents<'_> {
+    /// Wait for the next client to join or leave the AP.
+    pub async fn next(&mut self) -> ApClientEvent {
+        loop {
+            let event = self.subscriber.next_message_pure().await;
+            let h = event.header;
+            if h.ifidx != self.iface {
+                continue;
+            }
+
+            let addr = h.addr;
+            let reason = h.reason as u16;
+            match h.event_type {
+                Event::ASSOC_IND => return ApClientEvent::Assoc { addr },
+                Event::REASSOC_IND => return ApClientEvent::Reassoc { addr },
+                Event::DISASSOC_IND => return ApClientEvent::Disassoc { addr, reason },
+                Event::DEAUTH_IND => return ApClientEvent::Deauth { addr, reason },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for ApClientEvents<'_> {
+    fn drop(&mut self) {
+        self.events.mask.disable(AP_CLIENT_EVENTS);
+    }
+}
+
+/// 20MHz chanspec for the given channel number.
+fn chanspec(channel: u8) -> u16 {
+    let band = if channel <= 14 {
+        CHANSPEC_BAND_2G
+    } else {
+        CHANSPEC_BAND_5G
+    };
+    (channel as u32 | band | CHANSPEC_BW_20 | CHANSPEC_CTL_SB_NONE) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+
+    use super::*;
+
+    #[test]
+    fn join_options_debug_redacts_passphrase() {
+        let options = JoinOptions::new("hunter22").channel(6);
+        let debug = format!("{:?}", options);
+        assert!(!debug.contains("hunter22"));
+        assert!(debug.contains("<redacted>"));
+        assert!(debug.contains("Some(6)"));
+    }
+
+    #[test]
+    fn scan_params_encoding() {
+        let u32_at = |b: &[u8], i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+
+        let options = ScanOptions::default()
+            .scan_type(ScanType::Active)
+            .ssid("net")
+            .channels(&[1, 6, 36])
+            .dwell_time(Duration::from_millis(40));
+        let bytes = options.scan_params().unwrap().to_bytes();
+        assert_eq!(bytes.len(), 100);
+        assert_eq!(u32_at(&bytes, 0), 1); // version
+        assert_eq!(&bytes[4..6], ESCAN_ACTION_START.to_le_bytes());
+        assert_eq!(u32_at(&bytes, 8), 3);
+        assert_eq!(&bytes[12..15], b"net");
+        assert_eq!(bytes[44..50], [0xff; 6]);
+        assert_eq!(bytes[51], 0); // active
+        assert_eq!(u32_at(&bytes, 52), !0); // nprobes
+        assert_eq!(u32_at(&bytes, 56), 40); // active time
+        assert_eq!(u32_at(&bytes, 60), !0); // passive time
+        assert_eq!(u32_at(&bytes, 64), !0); // home time
+        assert_eq!(u32_at(&bytes, 68), 3);
+        assert_eq!(bytes[72..78], [0x01, 0x10, 0x06, 0x10, 0x24, 0xd0]);
+        assert_eq!(bytes[78..100], [0; 22]);
+
+        let options = ScanOptions::default()
+            .bssid([1, 2, 3, 4, 5, 6])
+            .nprobes(2)
+            .dwell_time(Duration::from_millis(120))
+            .home_time(Duration::from_millis(50));
+        let bytes = options.scan_params().unwrap().to_bytes();
+        assert_eq!(u32_at(&bytes, 8), 0);
+        assert_eq!(bytes[44..50], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(bytes[51], 1); // passive
+        assert_eq!(u32_at(&bytes, 52), 2);
+        assert_eq!(u32_at(&bytes, 56), !0);
+        assert_eq!(u32_at(&bytes, 60), 120);
+        assert_eq!(u32_at(&bytes, 64), 50);
+        assert_eq!(u32_at(&bytes, 68), 0);
+
+        let err = ScanOptions::default().channels(&[1; 15]).scan_params();
+        assert!(matches!(err, Err(Error::InvalidScanOptions)));
+    }
+
+    #[test]
+    fn link_counters_parse() {
+        let mut buf = [0; 4 + 18 * 4];
+        buf[0..2].copy_from_slice(&10u16.to_le_bytes());
+        for (i, word) in buf[4..].chunks_exact_mut(4).enumerate() {
+            word.copy_from_slice(&(i as u32 + 100).to_le_bytes());
+        }
+
+        let counters = LinkCounters::parse(&buf).unwrap();
+        assert_eq!(counters.tx_frames, 100);
+        assert_eq!(counters.tx_errors, 103);
+        assert_eq!(counters.rx_frames, 115);
+        assert_eq!(counters.rx_errors, 117);
+
+        assert_eq!(LinkCounters::parse(&buf[..40]), Err(Error::ShortResponse));
+        assert_eq!(LinkCounters::parse(&buf[..1]), Err(Error::ShortResponse));
+
+        // `wl_cnt_info_t`, with XTLVs instead of the flat layout.
+        buf[0..2].copy_from_slice(&30u16.to_le_bytes());
+        assert_eq!(
+            LinkCounters::parse(&buf),
+            Err(Error::UnsupportedCounters { version: 30 })
+        );
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn set_tx_power_range() {
+        use std::vec::Vec;
+
+        use crate::sim::tests::with_sim;
+        use crate::sim::Sim;
+
+        with_sim(Sim::new(), |_net, mut control, sim| async move {
+            assert_eq!(control.set_tx_power(32).await, Err(Error::InvalidTxPower));
+            assert_eq!(sim.iovar("qtxpower"), None);
+
+            control.set_tx_power(31).await.unwrap();
+            assert_eq!(sim.iovar("qtxpower"), Some(Vec::from(124u32.to_le_bytes())));
+            assert_eq!(control.get_tx_power().await, Ok(31));
+        });
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn scan_cancelled_while_starting() {
+        use crate::sim::tests::{escan_action, with_sim};
+        use crate::sim::{IoctlReply, Sim, SimEvent};
+
+        let sim = Sim::new();
+        let mut hung = false;
+        sim.on_ioctl(move |req| match escan_action(req)? {
+            ESCAN_ACTION_START if !hung => {
+                hung = true;
+                Some(IoctlReply::no_response())
+            }
+            ESCAN_ACTION_START => Some(IoctlReply::ok().event(SimEvent::scan_complete())),
+            _ => None,
+        });
+
+        with_sim(sim, |_net, mut control, _sim| async move {
+            // Cancelled while waiting for the escan iovar.
+            assert!(with_timeout(Duration::from_millis(10), control.scan()).await.is_err());
+            assert!(!control.events.scan_active.get());
+            assert!(!control.events.mask.is_enabled(Event::ESCAN_RESULT));
+
+            let mut scanner = control.scan().await.unwrap();
+            assert!(scanner.next().await.is_none());
+            drop(scanner);
+            assert!(!control.events.mask.is_enabled(Event::ESCAN_RESULT));
+        });
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn link_info() {
+        use std::vec::Vec;
+
+        use crate::sim::tests::with_sim;
+        use crate::sim::Sim;
+
+        let sim = Sim::new();
+        sim.set_ioctl(IOCTL_CMD_GET_BSSID, &[0, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        let mut ssid = Vec::from(7u32.to_le_bytes());
+        ssid.extend_from_slice(b"network");
+        sim.set_ioctl(IOCTL_CMD_GET_SSID, &ssid);
+        sim.set_ioctl(IOCTL_CMD_GET_RSSI, &(-50i32).to_le_bytes());
+        sim.set_ioctl(IOCTL_CMD_GET_PHY_NOISE, &(-90i32).to_le_bytes());
+        sim.set_ioctl(IOCTL_CMD_GET_RATE, &144u32.to_le_bytes());
+        sim.set_ioctl(IOCTL_CMD_GET_CHANNEL, &[6, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut counters = Vec::from(10u16.to_le_bytes());
+        counters.extend_from_slice(&[0; 2]);
+        for i in 0..18 {
+            counters.extend_from_slice(&(i as u32 + 100).to_le_bytes());
+        }
+        sim.set_iovar("counters", &counters);
+
+        with_sim(sim, |_net, mut control, sim| async move {
+            let info = control.link_info().await.unwrap();
+            assert_eq!(info.bssid, [0, 0x11, 0x22, 0x33, 0x44, 0x55]);
+            assert_eq!(info.ssid(), b"network");
+            assert_eq!(info.rssi, -50);
+            assert_eq!(info.snr(), 40);
+            assert_eq!(info.rate_kbps, 72_000);
+            assert_eq!(info.channel, 6);
+            assert_eq!(info.counters.tx_frames, 100);
+            assert_eq!(info.counters.rx_errors, 117);
+
+            let log = sim.ioctl_log();
+            let req = log.iter().find(|req| req.iovar_name() == Some("counters")).unwrap();
+            assert_eq!(req.data.len(), LinkCounters::BUF_LEN);
+
+            // Newer firmware, with `wl_cnt_info_t`.
+            sim.set_iovar("counters", &30u16.to_le_bytes());
+            let err = control.link_info().await;
+            assert_eq!(err, Err(Error::UnsupportedCounters { version: 30 }));
+        });
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn set_country() {
+        use std::vec::Vec;
+
+        use crate::countries;
+        use crate::sim::tests::with_sim;
+        use crate::sim::Sim;
+
+        fn country_info(abbrev: &[u8; 4], rev: i32, ccode: &[u8; 4]) -> Vec<u8> {
+            let mut res = Vec::from(*abbrev);
+            res.extend_from_slice(&rev.to_le_bytes());
+            res.extend_from_slice(ccode);
+            res
+        }
+
+        with_sim(Sim::new(), |_net, mut control, sim| async move {
+            // Set by `init`. Revision 0 means the CLM default, which the firmware takes as -1.
+            let info = country_info(b"XX\0\0", -1, b"XX\0\0");
+            assert_eq!(sim.iovar("country"), Some(info));
+
+            control.set_country(countries::GERMANY).await.unwrap();
+            let info = country_info(b"DE\0\0", -1, b"DE\0\0");
+            assert_eq!(sim.iovar("country"), Some(info));
+
+            control.set_country(countries::WORLD_WIDE_XX_REV17).await.unwrap();
+            let info = country_info(b"XX\0\0", 17, b"XX\0\0");
+            assert_eq!(sim.iovar("country"), Some(info));
+        });
     }
 }
@@ -1,10 +1,25 @@
-#![allow(unused)]
+//! Country codes for the regulatory domain, for [`crate::Control::init`] and
+//! [`crate::Control::set_country`].
+//!
+//! The allowed channels and transmit power limits for each country are defined by the CLM
+//! blob loaded in `init`.
 
+/// A country code with its regulatory revision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Country {
+    /// ISO 3166-1 alpha-2 code, or `XX` for the worldwide domain.
     pub code: [u8; 2],
+    /// Regulatory revision. 0 uses the default revision for the country in the CLM.
     pub rev: u16,
 }
 
+impl Country {
+    pub const fn new(code: [u8; 2], rev: u16) -> Self {
+        Self { code, rev }
+    }
+}
+
 /// AF Afghanistan
 pub const AFGHANISTAN: Country = Country { code: *b"AF", rev: 0 };
 /// AL Albania
@@ -1,12 +1,14 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
 
-use crate::structs::BssInfo;
+use crate::ioctl::IoctlState;
+use crate::scan::ScanResult;
+use crate::structs;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::FromPrimitive)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -286,13 +288,30 @@ pub enum Event {
     LAST = 190,
 }
 
+/// Number of events the queue holds before the oldest one is dropped.
+///
+/// Set with the `event-queue-depth-*` cargo features.
+pub const EVENT_QUEUE_DEPTH: usize = if cfg!(feature = "event-queue-depth-32") {
+    32
+} else if cfg!(feature = "event-queue-depth-16") {
+    16
+} else if cfg!(feature = "event-queue-depth-8") {
+    8
+} else {
+    4
+};
+
 // TODO this PubSub can probably be replaced with shared memory to make it a bit more efficient.
-pub type EventQueue = PubSubChannel<NoopRawMutex, Message, 2, 1, 1>;
-pub type EventSubscriber<'a> = Subscriber<'a, NoopRawMutex, Message, 2, 1, 1>;
+pub type EventQueue = PubSubChannel<NoopRawMutex, Message, EVENT_QUEUE_DEPTH, 4, 1>;
+pub type EventSubscriber<'a> = Subscriber<'a, NoopRawMutex, Message, EVENT_QUEUE_DEPTH, 4, 1>;
 
 pub struct Events {
     pub queue: EventQueue,
     pub mask: SharedEventMask,
+    pub link: LinkTracker,
+    /// An escan is running on the firmware. Cleared by the `Runner` when the final `ESCAN_RESULT` arrives.
+    pub scan_active: Cell<bool>,
+    pub stats: EventCounters,
 }
 
 impl Events {
@@ -300,21 +319,98 @@ impl Events {
         Self {
             queue: EventQueue::new(),
             mask: SharedEventMask::default(),
+            link: LinkTracker::default(),
+            scan_active: Cell::new(false),
+            stats: EventCounters::default(),
         }
     }
 }
 
+/// Event delivery counters, updated by the `Runner`.
+#[derive(Default)]
+pub struct EventCounters {
+    pub delivered: Cell<u32>,
+    pub dropped: Cell<u32>,
+}
+
+impl EventCounters {
+    pub fn snapshot(&self) -> EventStats {
+        EventStats {
+            delivered: self.delivered.get(),
+            dropped: self.dropped.get(),
+        }
+    }
+}
+
+/// Event delivery statistics, returned by [`crate::Control::event_stats`].
+///
+/// The counters wrap around on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EventStats {
+    /// Events put in the queue without dropping any.
+    pub delivered: u32,
+    /// Events that caused the oldest queued event to be dropped, because subscribers didn't
+    /// read the queue in time.
+    pub dropped: u32,
+}
+
+/// Why the station link went down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisconnectReason {
+    /// The AP deauthenticated us. `reason` is the 802.11 reason code.
+    Deauth { reason: u16 },
+    /// The AP disassociated us. `reason` is the 802.11 reason code.
+    Disassoc { reason: u16 },
+    /// Beacons from the AP stopped arriving.
+    BeaconLost,
+    /// The firmware reported the link as down.
+    LinkDown { reason: u32 },
+}
+
+/// Station link state, tracked by the `Runner` from events regardless of the event mask.
+#[derive(Default)]
+pub struct LinkTracker {
+    /// Set while we are joined to a network, until we leave it.
+    pub joined: Cell<bool>,
+    /// Why the link last went down, cleared when it comes back up.
+    pub down_reason: Cell<Option<DisconnectReason>>,
+}
+
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Status {
     pub event_type: Event,
     pub status: u32,
+    pub reason: u32,
+    /// Station address, for events concerning another station.
+    pub addr: [u8; 6],
+    /// Interface the event happened on.
+    pub ifidx: u8,
 }
 
 #[derive(Clone, Copy)]
 pub enum Payload {
     None,
-    BssInfo(BssInfo),
+    ScanResult(ScanResult),
+    Data(EventData),
+}
+
+/// Raw event data, for events without a parsed payload.
+#[derive(Clone, Copy)]
+pub struct EventData {
+    pub len: usize,
+    pub buf: [u8; MAX_EVENT_PAYLOAD],
+}
+
+impl EventData {
+    pub fn new(data: &[u8]) -> Self {
+        let len = data.len().min(MAX_EVENT_PAYLOAD);
+        let mut buf = [0; MAX_EVENT_PAYLOAD];
+        buf[..len].copy_from_slice(&data[..len]);
+        Self { len, buf }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -333,8 +429,18 @@ impl Message {
     }
 }
 
-#[derive(Default)]
-struct EventMask {
+/// Spammy, uninteresting events that are disabled in the firmware unless someone subscribes to them.
+const FIRMWARE_MASKED_EVENTS: &[Event] = &[
+    Event::RADIO,
+    Event::IF,
+    Event::PROBREQ_MSG,
+    Event::PROBREQ_MSG_RX,
+    Event::PROBRESP_MSG,
+    Event::ROAM,
+];
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct EventMask {
     mask: [u32; Self::WORD_COUNT],
 }
 
@@ -345,56 +451,296 @@ impl EventMask {
         let n = event as u32;
         let word = n / u32::BITS;
         let bit = n % u32::BITS;
-
         self.mask[word as usize] |= 1 << bit;
     }
 
-    fn disable(&mut self, event: Event) {
-        let n = event as u32;
-        let word = n / u32::BITS;
-        let bit = n % u32::BITS;
-
-        self.mask[word as usize] &= !(1 << bit);
-    }
-
     fn is_enabled(&self, event: Event) -> bool {
         let n = event as u32;
         let word = n / u32::BITS;
         let bit = n % u32::BITS;
+        // `Unknown` is out of range.
+        self.mask.get(word as usize).map_or(false, |w| w & (1 << bit) > 0)
+    }
 
-        self.mask[word as usize] & (1 << bit) > 0
+    fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        (0..Event::LAST as u8)
+            .map(Event::from)
+            .filter(|event| self.is_enabled(*event))
     }
 }
 
-#[derive(Default)]
+impl FromIterator<Event> for EventMask {
+    fn from_iter<I: IntoIterator<Item = Event>>(events: I) -> Self {
+        let mut mask = Self::default();
+        for event in events {
+            // `Unknown` is out of range, and never delivered anyway.
+            if (event as u32) < Event::LAST as u32 {
+                mask.enable(event);
+            }
+        }
+        mask
+    }
+}
 
+/// Event mask shared by everything waiting for events, with a reference count per event so
+/// that one user disabling an event doesn't disable it for the others.
 pub struct SharedEventMask {
-    mask: RefCell<EventMask>,
+    counts: RefCell<[u8; 256]>,
+}
+
+impl Default for SharedEventMask {
+    fn default() -> Self {
+        Self {
+            counts: RefCell::new([0; 256]),
+        }
+    }
 }
 
 impl SharedEventMask {
     pub fn enable(&self, events: &[Event]) {
-        let mut mask = self.mask.borrow_mut();
+        let mut counts = self.counts.borrow_mut();
         for event in events {
-            mask.enable(*event);
+            let count = &mut counts[*event as u8 as usize];
+            *count = count.saturating_add(1);
         }
     }
 
-    #[allow(dead_code)]
     pub fn disable(&self, events: &[Event]) {
-        let mut mask = self.mask.borrow_mut();
+        let mut counts = self.counts.borrow_mut();
         for event in events {
-            mask.disable(*event);
+            let count = &mut counts[*event as u8 as usize];
+            *count = count.saturating_sub(1);
         }
     }
 
     pub fn disable_all(&self) {
-        let mut mask = self.mask.borrow_mut();
-        mask.mask = Default::default();
+        let mut counts = self.counts.borrow_mut();
+        *counts = [0; 256];
     }
 
     pub fn is_enabled(&self, event: Event) -> bool {
-        let mask = self.mask.borrow();
-        mask.is_enabled(event)
+        self.counts.borrow()[event as u8 as usize] > 0
+    }
+
+    /// Mask to set with the `bsscfg:event_msgs` iovar. All events are enabled in the firmware,
+    /// except for spammy ones nobody is subscribed to.
+    pub fn firmware_mask(&self) -> structs::EventMask {
+        let mut mask = structs::EventMask {
+            iface: 0,
+            events: [0xFF; 24],
+        };
+        for &event in FIRMWARE_MASKED_EVENTS {
+            if !self.is_enabled(event) {
+                mask.unset(event);
+            }
+        }
+        mask
+    }
+}
+
+/// Maximum number of payload bytes kept for each event in an [`EventMessage`].
+pub const MAX_EVENT_PAYLOAD: usize = 256;
+
+/// A firmware event, as delivered by [`crate::Control::subscribe_events`].
+#[derive(Clone, Copy)]
+pub struct EventMessage {
+    pub event_type: Event,
+    /// Status code. 0 means success.
+    pub status: u32,
+    /// Reason code, if applicable. For deauth and disassoc events this is the 802.11 reason code.
+    pub reason: u32,
+    /// Station address, if applicable.
+    pub addr: [u8; 6],
+    /// Interface the event happened on.
+    pub ifidx: u8,
+    payload_len: usize,
+    payload: [u8; MAX_EVENT_PAYLOAD],
+}
+
+impl EventMessage {
+    /// Event data following the event header, truncated to [`MAX_EVENT_PAYLOAD`] bytes.
+    ///
+    /// This is empty for `ESCAN_RESULT` events, use [`crate::Control::scan`] to get scan results.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_len]
+    }
+}
+
+impl From<&Message> for EventMessage {
+    fn from(msg: &Message) -> Self {
+        let h = msg.header;
+        let mut res = Self {
+            event_type: h.event_type,
+            status: h.status,
+            reason: h.reason,
+            addr: h.addr,
+            ifidx: h.ifidx,
+            payload_len: 0,
+            payload: [0; MAX_EVENT_PAYLOAD],
+        };
+        if let Payload::Data(data) = &msg.payload {
+            res.payload_len = data.len;
+            res.payload[..data.len].copy_from_slice(&data.buf[..data.len]);
+        }
+        res
+    }
+}
+
+/// A stream of firmware events, returned by [`crate::Control::subscribe_events`].
+///
+/// The events are enabled in the firmware for as long as the stream exists.
+pub struct EventStream<'a> {
+    subscriber: EventSubscriber<'a>,
+    events: &'a Events,
+    ioctl_state: &'a IoctlState,
+    /// The events this stream holds a reference on in the shared mask, each exactly once.
+    mask: EventMask,
+    lagged: u64,
+}
+
+impl<'a> EventStream<'a> {
+    pub(crate) fn new(
+        subscriber: EventSubscriber<'a>,
+        events: &'a Events,
+        ioctl_state: &'a IoctlState,
+        enabled: &[Event],
+    ) -> Self {
+        let mask: EventMask = enabled.iter().copied().collect();
+        for event in mask.iter() {
+            events.mask.enable(&[event]);
+        }
+        let res = Self {
+            subscriber,
+            events,
+            ioctl_state,
+            mask,
+            lagged: 0,
+        };
+        res.update_firmware_mask();
+        res
+    }
+
+    /// Have the runner update the mask in the firmware, if it masks any of our events by default.
+    fn update_firmware_mask(&self) {
+        if FIRMWARE_MASKED_EVENTS.iter().any(|e| self.mask.is_enabled(*e)) {
+            self.ioctl_state.request_event_mask_update();
+        }
+    }
+
+    fn contains(&self, event: Event) -> bool {
+        self.mask.is_enabled(event)
+    }
+
+    /// Wait for the next event.
+    pub async fn next(&mut self) -> EventMessage {
+        loop {
+            match self.subscriber.next_message().await {
+                WaitResult::Message(msg) if self.contains(msg.header.event_type) => {
+                    return EventMessage::from(&msg);
+                }
+                WaitResult::Message(_) => {}
+                WaitResult::Lagged(n) => self.lagged += n,
+            }
+        }
+    }
+
+    /// Number of events this stream missed because it wasn't read fast enough.
+    ///
+    /// This also counts missed events of types the stream isn't subscribed to, since all
+    /// subscribers share one queue.
+    pub fn lagged(&self) -> u64 {
+        self.lagged
+    }
+}
+
+impl Drop for EventStream<'_> {
+    fn drop(&mut self) {
+        for event in self.mask.iter() {
+            self.events.mask.disable(&[event]);
+        }
+        self.update_firmware_mask();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream<'a>(events: &'a Events, ioctl_state: &'a IoctlState, enabled: &[Event]) -> EventStream<'a> {
+        EventStream::new(events.queue.subscriber().unwrap(), events, ioctl_state, enabled)
+    }
+
+    #[test]
+    fn shared_mask_refcount() {
+        let mask = SharedEventMask::default();
+        mask.enable(&[Event::LINK, Event::DISASSOC]);
+        mask.enable(&[Event::LINK]);
+        assert!(mask.is_enabled(Event::LINK) && mask.is_enabled(Event::DISASSOC));
+
+        mask.disable(&[Event::LINK, Event::DISASSOC]);
+        assert!(mask.is_enabled(Event::LINK) && !mask.is_enabled(Event::DISASSOC));
+
+        // Unbalanced disables don't underflow.
+        mask.disable(&[Event::DISASSOC]);
+        mask.enable(&[Event::DISASSOC]);
+        assert!(mask.is_enabled(Event::DISASSOC));
+
+        mask.disable_all();
+        assert!(!mask.is_enabled(Event::LINK) && !mask.is_enabled(Event::DISASSOC));
+    }
+
+    #[test]
+    fn overlapping_streams() {
+        let events = Events::new();
+        let ioctl_state = IoctlState::new();
+
+        // Duplicates only take one reference.
+        let a = stream(&events, &ioctl_state, &[Event::LINK, Event::DISASSOC, Event::LINK]);
+        let b = stream(&events, &ioctl_state, &[Event::LINK]);
+        events.mask.enable(&[Event::DISASSOC]);
+
+        drop(a);
+        assert!(events.mask.is_enabled(Event::LINK));
+        assert!(events.mask.is_enabled(Event::DISASSOC));
+
+        drop(b);
+        assert!(!events.mask.is_enabled(Event::LINK));
+        assert!(events.mask.is_enabled(Event::DISASSOC));
+
+        events.mask.disable(&[Event::DISASSOC]);
+        assert!(!events.mask.is_enabled(Event::DISASSOC));
+    }
+
+    #[test]
+    fn stream_ignores_unknown() {
+        let events = Events::new();
+        let ioctl_state = IoctlState::new();
+        let s = stream(&events, &ioctl_state, &[Event::Unknown, Event::ROAM]);
+        assert!(s.contains(Event::ROAM));
+        assert!(!s.contains(Event::Unknown));
+        assert!(!s.contains(Event::LINK));
+    }
+
+    #[test]
+    fn firmware_mask() {
+        let bit = |mask: &structs::EventMask, event: Event| {
+            let n = event as usize;
+            mask.events[n / 8] & (1 << (n % 8)) != 0
+        };
+
+        let events = Events::new();
+        let ioctl_state = IoctlState::new();
+        let mask = events.mask.firmware_mask();
+        assert!(bit(&mask, Event::LINK));
+        assert!(!bit(&mask, Event::PROBREQ_MSG));
+        assert!(!bit(&mask, Event::ROAM));
+
+        let s = stream(&events, &ioctl_state, &[Event::PROBREQ_MSG]);
+        let mask = events.mask.firmware_mask();
+        assert!(bit(&mask, Event::PROBREQ_MSG));
+        assert!(!bit(&mask, Event::ROAM));
+
+        drop(s);
+        assert!(!bit(&events.mask.firmware_mask(), Event::PROBREQ_MSG));
     }
 }
@@ -1,10 +1,20 @@
+#![forbid(unsafe_code)]
+
 use core::cell::{Cell, RefCell};
 use core::future::poll_fn;
 use core::task::{Poll, Waker};
 
 use embassy_sync::waitqueue::WakerRegistration;
 
+use crate::consts::{Bcme, STATUS_F2_PKT_LEN_MASK, STATUS_F2_PKT_LEN_SHIFT};
 use crate::fmt::Bytes;
+use crate::structs::{CdcHeader, SdpcmHeader};
+
+/// Maximum length of an ioctl buffer, so that the request and the response each fit in one
+/// SDPCM frame with the SDPCM and CDC headers. Received frames are limited by the length field
+/// of the status register, which is one byte short of 2048.
+pub const MAX_IOCTL_LEN: usize =
+    (STATUS_F2_PKT_LEN_MASK >> STATUS_F2_PKT_LEN_SHIFT) as usize - SdpcmHeader::SIZE - CdcHeader::SIZE;
 
 #[derive(Clone, Copy)]
 pub enum IoctlType {
@@ -14,17 +24,18 @@ pub enum IoctlType {
 
 #[derive(Clone, Copy)]
 pub struct PendingIoctl {
-    pub buf: *mut [u8],
     pub kind: IoctlType,
     pub cmd: u32,
     pub iface: u32,
+    /// Length of the request in the `IoctlState` buffer.
+    pub len: usize,
 }
 
 #[derive(Clone, Copy)]
 enum IoctlStateInner {
     Pending(PendingIoctl),
-    Sent { buf: *mut [u8] },
-    Done { resp_len: usize },
+    Sent,
+    Done { result: Result<usize, Bcme> },
 }
 
 struct Wakers {
@@ -43,14 +54,29 @@ impl Default for Wakers {
 
 pub struct IoctlState {
     state: Cell<IoctlStateInner>,
+    /// Holds the request until the runner sends it, then the response. Owning it here instead of
+    /// pointing into the caller's buffer keeps it valid if the caller cancels the ioctl.
+    buf: RefCell<[u8; MAX_IOCTL_LEN]>,
     wakers: RefCell<Wakers>,
+    scan_abort: Cell<bool>,
+    event_mask_update: Cell<bool>,
+}
+
+/// Something the runner has to send an ioctl for on its own, see [`IoctlState::wait_runner_request`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RunnerRequest {
+    ScanAbort,
+    EventMaskUpdate,
 }
 
 impl IoctlState {
     pub fn new() -> Self {
         Self {
-            state: Cell::new(IoctlStateInner::Done { resp_len: 0 }),
+            state: Cell::new(IoctlStateInner::Done { result: Ok(0) }),
+            buf: RefCell::new([0; MAX_IOCTL_LEN]),
             wakers: Default::default(),
+            scan_abort: Cell::new(false),
+            event_mask_update: Cell::new(false),
         }
     }
 
@@ -70,10 +96,10 @@ impl IoctlState {
         self.wakers.borrow_mut().runner.register(waker);
     }
 
-    pub async fn wait_complete(&self) -> usize {
+    pub async fn wait_complete(&self) -> Result<usize, Bcme> {
         poll_fn(|cx| {
-            if let IoctlStateInner::Done { resp_len } = self.state.get() {
-                Poll::Ready(resp_len)
+            if let IoctlStateInner::Done { result } = self.state.get() {
+                Poll::Ready(result)
             } else {
                 self.register_control(cx.waker());
                 Poll::Pending
@@ -93,34 +119,95 @@ impl IoctlState {
         })
         .await;
 
-        self.state.set(IoctlStateInner::Sent { buf: pending.buf });
+        self.state.set(IoctlStateInner::Sent);
         pending
     }
 
+    /// Copy the request of `pending` into `dst`, returning its length.
+    pub fn copy_request(&self, pending: &PendingIoctl, dst: &mut [u8]) -> usize {
+        let len = pending.len.min(dst.len());
+        dst[..len].copy_from_slice(&self.buf.borrow()[..len]);
+        len
+    }
+
+    /// Ask the runner to abort the running escan. This can't be done from `Scanner::drop`
+    /// directly since it can't wait for the ioctl to complete.
+    pub fn request_scan_abort(&self) {
+        self.scan_abort.set(true);
+        self.wake_runner();
+    }
+
+    /// Ask the runner to update the event mask in the firmware, after the events subscribed to changed.
+    pub fn request_event_mask_update(&self) {
+        self.event_mask_update.set(true);
+        self.wake_runner();
+    }
+
+    /// Wait until a request is made and no ioctl is in flight, so the runner can send it
+    /// without its response being confused with the one for a `Control` ioctl.
+    pub async fn wait_runner_request(&self) -> RunnerRequest {
+        poll_fn(|cx| {
+            if !matches!(self.state.get(), IoctlStateInner::Done { .. }) {
+                self.register_runner(cx.waker());
+                Poll::Pending
+            } else if self.scan_abort.replace(false) {
+                Poll::Ready(RunnerRequest::ScanAbort)
+            } else if self.event_mask_update.replace(false) {
+                Poll::Ready(RunnerRequest::EventMaskUpdate)
+            } else {
+                self.register_runner(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
     pub fn cancel_ioctl(&self) {
-        self.state.set(IoctlStateInner::Done { resp_len: 0 });
+        self.state.set(IoctlStateInner::Done { result: Ok(0) });
     }
 
-    pub async fn do_ioctl(&self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> usize {
+    /// Send an ioctl. `buf` must be at most `MAX_IOCTL_LEN` long.
+    pub async fn do_ioctl(&self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> Result<usize, Bcme> {
+        let len = buf.len();
+        self.buf.borrow_mut()[..len].copy_from_slice(buf);
         self.state
-            .set(IoctlStateInner::Pending(PendingIoctl { buf, kind, cmd, iface }));
+            .set(IoctlStateInner::Pending(PendingIoctl { kind, cmd, iface, len }));
         self.wake_runner();
-        self.wait_complete().await
+
+        let resp_len = self.wait_complete().await?;
+
+        // The response may be longer than the request, for example with GET_VAR the
+        // firmware may return more than the caller asked for. Copy what fits.
+        let copy_len = resp_len.min(len);
+        if copy_len < resp_len {
+            debug!("IOCTL response truncated from {} to {}", resp_len, copy_len);
+        }
+        buf[..copy_len].copy_from_slice(&self.buf.borrow()[..copy_len]);
+        Ok(copy_len)
     }
 
     pub fn ioctl_done(&self, response: &[u8]) {
-        if let IoctlStateInner::Sent { buf } = self.state.get() {
+        if let IoctlStateInner::Sent = self.state.get() {
             trace!("IOCTL Response: {:02x}", Bytes(response));
 
-            // TODO fix this
-            (unsafe { &mut *buf }[..response.len()]).copy_from_slice(response);
+            let len = response.len().min(MAX_IOCTL_LEN);
+            self.buf.borrow_mut()[..len].copy_from_slice(&response[..len]);
 
-            self.state.set(IoctlStateInner::Done {
-                resp_len: response.len(),
-            });
+            self.state.set(IoctlStateInner::Done { result: Ok(len) });
             self.wake_control();
+            self.wake_runner();
         } else {
             warn!("IOCTL Response but no pending Ioctl");
         }
     }
+
+    pub fn ioctl_failed(&self, error: Bcme) {
+        if let IoctlStateInner::Sent = self.state.get() {
+            self.state.set(IoctlStateInner::Done { result: Err(error) });
+            self.wake_control();
+            self.wake_runner();
+        } else {
+            warn!("IOCTL error but no pending Ioctl");
+        }
+    }
 }
@@ -1,15 +1,18 @@
 #![no_std]
-#![no_main]
 #![allow(incomplete_features)]
 #![feature(async_fn_in_trait, type_alias_impl_trait, concat_bytes)]
 #![deny(unused_must_use)]
 
+#[cfg(any(test, feature = "sim"))]
+extern crate std;
+
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+mod bluetooth;
 mod bus;
 mod consts;
-mod countries;
+pub mod countries;
 mod events;
 mod ioctl;
 mod structs;
@@ -17,6 +20,13 @@ mod structs;
 mod control;
 mod nvram;
 mod runner;
+mod scan;
+#[cfg(feature = "sim")]
+pub mod sim;
+
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
 
 use core::slice;
 
@@ -25,11 +35,22 @@ use embedded_hal_1::digital::OutputPin;
 use events::Events;
 use ioctl::IoctlState;
 
+use crate::bluetooth::BtRunner;
+pub use crate::bluetooth::{BtDriver, BtError, BtState, HciPacketType, BT_HCI_MTU};
 use crate::bus::Bus;
 pub use crate::bus::SpiBusCyw43;
-pub use crate::control::{Control, Error as ControlError};
+pub use crate::consts::Bcme;
+pub use crate::control::{
+    ApClientEvent, ApClientEvents, Control, Error as ControlError, JoinAuth, JoinOptions, LinkCounters, LinkInfo,
+    ScanOptions, ScanType, Scanner,
+};
+pub use crate::events::{
+    DisconnectReason, Event, EventMessage, EventStats, EventStream, EVENT_QUEUE_DEPTH, MAX_EVENT_PAYLOAD,
+};
+pub use crate::ioctl::MAX_IOCTL_LEN;
 pub use crate::runner::Runner;
-pub use crate::structs::BssInfo;
+pub use crate::scan::{Band, Bandwidth, IeIter, ScanEntry, ScanResult, ScanTable, SecuritySummary};
+pub use crate::structs::{BssInfo, ParseError};
 
 const MTU: usize = 1514;
 
@@ -42,66 +63,130 @@ enum Core {
 }
 
 impl Core {
-    fn base_addr(&self) -> u32 {
+    fn base_addr(&self, chip: &Chip) -> u32 {
         match self {
-            Self::WLAN => CHIP.arm_core_base_address,
-            Self::SOCSRAM => CHIP.socsram_wrapper_base_address,
-            Self::SDIOD => CHIP.sdiod_core_base_address,
+            Self::WLAN => chip.arm_core_base_address,
+            Self::SOCSRAM => unwrap!(chip.socsram_base_address) + WRAPPER_REGISTER_OFFSET,
+            Self::SDIOD => chip.sdiod_core_base_address,
         }
     }
 }
 
-#[allow(unused)]
-struct Chip {
-    arm_core_base_address: u32,
-    socsram_base_address: u32,
-    socsram_wrapper_base_address: u32,
-    sdiod_core_base_address: u32,
-    pmu_base_address: u32,
-    chip_ram_size: u32,
-    atcm_ram_base_address: u32,
-    socram_srmem_size: u32,
-    chanspec_band_mask: u32,
-    chanspec_band_2g: u32,
-    chanspec_band_5g: u32,
-    chanspec_band_shift: u32,
-    chanspec_bw_10: u32,
-    chanspec_bw_20: u32,
-    chanspec_bw_40: u32,
-    chanspec_bw_mask: u32,
-    chanspec_bw_shift: u32,
-    chanspec_ctl_sb_lower: u32,
-    chanspec_ctl_sb_upper: u32,
-    chanspec_ctl_sb_none: u32,
-    chanspec_ctl_sb_mask: u32,
+/// The ARM core running the WLAN firmware, which determines how it is booted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArmCore {
+    /// Cortex-M3, running from SOCSRAM.
+    CortexM3,
+    /// Cortex-R4, running from TCM. Started through a reset vector at address 0.
+    CortexR4,
+}
+
+/// Description of a supported chip: its ID and backplane memory map.
+///
+/// The chip is detected from its ID when the driver starts, see [`Runner::chip`]. The NVRAM
+/// the driver loads is the one for the Raspberry Pi Pico W, other modules may need their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Chip {
+    /// Chip ID, as read from the ChipCommon core.
+    pub id: u16,
+    pub name: &'static str,
+    pub arm_core: ArmCore,
+    /// Base address of the ARM core's wrapper registers.
+    pub arm_core_base_address: u32,
+    /// Base address of the SOCSRAM core, for chips running from SOCSRAM.
+    pub socsram_base_address: Option<u32>,
+    pub sdiod_core_base_address: u32,
+    pub pmu_base_address: u32,
+    /// Base address of the Bluetooth core, for chips with Bluetooth on the same bus as WLAN.
+    pub bluetooth_base_address: Option<u32>,
+    pub chip_ram_size: u32,
+    pub atcm_ram_base_address: u32,
+    pub socram_srmem_size: u32,
 }
 
 const WRAPPER_REGISTER_OFFSET: u32 = 0x100000;
 
-// Data for CYW43439
-const CHIP: Chip = Chip {
-    arm_core_base_address: 0x18003000 + WRAPPER_REGISTER_OFFSET,
-    socsram_base_address: 0x18004000,
-    socsram_wrapper_base_address: 0x18004000 + WRAPPER_REGISTER_OFFSET,
-    sdiod_core_base_address: 0x18002000,
-    pmu_base_address: 0x18000000,
-    chip_ram_size: 512 * 1024,
-    atcm_ram_base_address: 0,
-    socram_srmem_size: 64 * 1024,
-    chanspec_band_mask: 0xc000,
-    chanspec_band_2g: 0x0000,
-    chanspec_band_5g: 0xc000,
-    chanspec_band_shift: 14,
-    chanspec_bw_10: 0x0800,
-    chanspec_bw_20: 0x1000,
-    chanspec_bw_40: 0x1800,
-    chanspec_bw_mask: 0x3800,
-    chanspec_bw_shift: 11,
-    chanspec_ctl_sb_lower: 0x0000,
-    chanspec_ctl_sb_upper: 0x0100,
-    chanspec_ctl_sb_none: 0x0000,
-    chanspec_ctl_sb_mask: 0x0700,
-};
+impl Chip {
+    pub const CYW43439: Chip = Chip {
+        id: 43439,
+        name: "CYW43439",
+        arm_core: ArmCore::CortexM3,
+        arm_core_base_address: 0x18003000 + WRAPPER_REGISTER_OFFSET,
+        socsram_base_address: Some(0x18004000),
+        sdiod_core_base_address: 0x18002000,
+        pmu_base_address: 0x18000000,
+        bluetooth_base_address: Some(0x19000000),
+        chip_ram_size: 512 * 1024,
+        atcm_ram_base_address: 0,
+        socram_srmem_size: 64 * 1024,
+    };
+
+    /// The CYW43438 reports the ID of the 43430 family. The CYW4343W reports the same ID and has
+    /// the same memory map, so it is detected and driven as a CYW43438. Bluetooth is on a
+    /// separate UART.
+    pub const CYW43438: Chip = Chip {
+        id: 43430,
+        name: "CYW43438",
+        arm_core: ArmCore::CortexM3,
+        arm_core_base_address: 0x18003000 + WRAPPER_REGISTER_OFFSET,
+        socsram_base_address: Some(0x18004000),
+        sdiod_core_base_address: 0x18002000,
+        pmu_base_address: 0x18000000,
+        bluetooth_base_address: None,
+        chip_ram_size: 512 * 1024,
+        atcm_ram_base_address: 0,
+        socram_srmem_size: 64 * 1024,
+    };
+
+    /// Bluetooth is on a separate UART.
+    pub const CYW43455: Chip = Chip {
+        id: 0x4345,
+        name: "CYW43455",
+        arm_core: ArmCore::CortexR4,
+        arm_core_base_address: 0x18002000 + WRAPPER_REGISTER_OFFSET,
+        socsram_base_address: None,
+        sdiod_core_base_address: 0x18004000,
+        pmu_base_address: 0x18000000,
+        bluetooth_base_address: None,
+        chip_ram_size: 0xc8000,
+        atcm_ram_base_address: 0x198000,
+        socram_srmem_size: 0,
+    };
+
+    /// All the chips the driver knows about.
+    pub const ALL: &'static [Chip] = &[Self::CYW43439, Self::CYW43438, Self::CYW43455];
+
+    /// Look up a chip by the ID it reports.
+    pub fn from_id(id: u16) -> Option<Chip> {
+        Self::ALL.iter().find(|chip| chip.id == id).copied()
+    }
+}
+
+/// Error returned when bringing up the chip fails, naming the stage that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitError {
+    /// The chip did not respond correctly to the gSPI bus test. Check the wiring and power.
+    BusTest,
+    /// The ALP (Active Low Power) clock did not become available.
+    AlpClock,
+    /// The firmware and NVRAM do not fit in the chip RAM.
+    FirmwareUpload,
+    /// The WLAN core did not come out of reset after loading the firmware.
+    CoreStart,
+    /// The HT (High Throughput) clock did not become available after starting the core.
+    /// This usually means the firmware is corrupt.
+    HtClock,
+    /// The firmware did not signal that F2 (WLAN data) is ready.
+    F2Ready,
+    /// The Bluetooth firmware is malformed, or the Bluetooth core did not come up.
+    Bluetooth,
+    /// The chip reported an ID the driver has no description for.
+    UnknownChip(u16),
+}
 
 pub struct State {
     ioctl_state: IoctlState,
@@ -119,6 +204,17 @@ impl State {
     }
 }
 
+/// State for the second network interface, used for the AP in concurrent AP+STA mode.
+pub struct ApState {
+    ch: ch::State<MTU, 4, 4>,
+}
+
+impl ApState {
+    pub fn new() -> Self {
+        Self { ch: ch::State::new() }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PowerManagementMode {
     /// Custom, officially unsupported mode. Use at your own risk.
@@ -211,7 +307,85 @@ pub async fn new<'a, PWR, SPI>(
     pwr: PWR,
     spi: SPI,
     firmware: &[u8],
-) -> (NetDriver<'a>, Control<'a>, Runner<'a, PWR, SPI>)
+) -> Result<(NetDriver<'a>, Control<'a>, Runner<'a, PWR, SPI>), InitError>
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let (ch_runner, device) = ch::new(&mut state.ch, [0; 6]);
+    let state_ch = ch_runner.state_runner();
+
+    let mut runner = Runner::new(
+        ch_runner,
+        None,
+        Bus::new(pwr, spi),
+        &state.ioctl_state,
+        &state.events,
+        None,
+    );
+
+    runner.init(firmware, None).await?;
+
+    Ok((
+        device,
+        Control::new(state_ch, None, &state.events, &state.ioctl_state),
+        runner,
+    ))
+}
+
+/// Like [`new`], but with a second network interface for running an AP concurrently with station mode.
+///
+/// Returns the station `NetDriver` first, then the AP one. The AP is started on this
+/// interface by [`Control::start_ap_open`] and [`Control::start_ap_wpa2`], while the station
+/// interface can join a network at the same time. The AP always uses the channel of the
+/// network the station is joined to, if any.
+pub async fn new_apsta<'a, PWR, SPI>(
+    state: &'a mut State,
+    ap_state: &'a mut ApState,
+    pwr: PWR,
+    spi: SPI,
+    firmware: &[u8],
+) -> Result<(NetDriver<'a>, NetDriver<'a>, Control<'a>, Runner<'a, PWR, SPI>), InitError>
+where
+    PWR: OutputPin,
+    SPI: SpiBusCyw43,
+{
+    let (ch_runner, device) = ch::new(&mut state.ch, [0; 6]);
+    let state_ch = ch_runner.state_runner();
+    let (ap_ch_runner, ap_device) = ch::new(&mut ap_state.ch, [0; 6]);
+    let ap_state_ch = ap_ch_runner.state_runner();
+
+    let mut runner = Runner::new(
+        ch_runner,
+        Some(ap_ch_runner),
+        Bus::new(pwr, spi),
+        &state.ioctl_state,
+        &state.events,
+        None,
+    );
+
+    runner.init(firmware, None).await?;
+
+    Ok((
+        device,
+        ap_device,
+        Control::new(state_ch, Some(ap_state_ch), &state.events, &state.ioctl_state),
+        runner,
+    ))
+}
+
+/// Like [`new`], but also brings up the Bluetooth core.
+///
+/// `bt_firmware` is the Bluetooth patch firmware (`43439A0_btfw.bin`), which is loaded
+/// after the WLAN firmware is running.
+pub async fn new_with_bluetooth<'a, PWR, SPI>(
+    state: &'a mut State,
+    bt_state: &'a BtState,
+    pwr: PWR,
+    spi: SPI,
+    firmware: &[u8],
+    bt_firmware: &[u8],
+) -> Result<(NetDriver<'a>, BtDriver<'a>, Control<'a>, Runner<'a, PWR, SPI>), InitError>
 where
     PWR: OutputPin,
     SPI: SpiBusCyw43,
@@ -219,15 +393,23 @@ where
     let (ch_runner, device) = ch::new(&mut state.ch, [0; 6]);
     let state_ch = ch_runner.state_runner();
 
-    let mut runner = Runner::new(ch_runner, Bus::new(pwr, spi), &state.ioctl_state, &state.events);
+    let mut runner = Runner::new(
+        ch_runner,
+        None,
+        Bus::new(pwr, spi),
+        &state.ioctl_state,
+        &state.events,
+        Some(BtRunner::new(bt_state)),
+    );
 
-    runner.init(firmware).await;
+    runner.init(firmware, Some(bt_firmware)).await?;
 
-    (
+    Ok((
         device,
-        Control::new(state_ch, &state.events, &state.ioctl_state),
+        BtDriver::new(bt_state),
+        Control::new(state_ch, None, &state.events, &state.ioctl_state),
         runner,
-    )
+    ))
 }
 
 fn slice8_mut(x: &mut [u32]) -> &mut [u8] {
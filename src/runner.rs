@@ -1,18 +1,22 @@
-use embassy_futures::select::{select3, Either3};
+use core::future::pending;
+
+use ch::driver::LinkState;
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_net_driver_channel as ch;
-use embassy_sync::pubsub::PubSubBehavior;
-use embassy_time::{block_for, Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal_1::digital::OutputPin;
 
+use crate::bluetooth::{BtRunner, BtWork};
 use crate::bus::Bus;
 pub use crate::bus::SpiBusCyw43;
 use crate::consts::*;
-use crate::events::{Event, Events, Status};
+use crate::events::{DisconnectReason, Event, Events, Status};
 use crate::fmt::Bytes;
-use crate::ioctl::{IoctlState, IoctlType, PendingIoctl};
+use crate::ioctl::{IoctlState, IoctlType, RunnerRequest};
 use crate::nvram::NVRAM;
+use crate::scan::ScanResult;
 use crate::structs::*;
-use crate::{events, slice8_mut, Core, CHIP, MTU};
+use crate::{events, slice8_mut, ArmCore, Chip, Core, InitError, MTU};
 
 #[cfg(feature = "firmware-logs")]
 struct LogState {
@@ -34,16 +38,29 @@ impl Default for LogState {
     }
 }
 
+/// How long to wait for each step of the chip bring-up before giving up.
+const INIT_TIMEOUT: Duration = Duration::from_millis(1000);
+
 pub struct Runner<'a, PWR, SPI> {
     ch: ch::Runner<'a, MTU>,
+    /// Second interface (bsscfg 1), for the AP in concurrent AP+STA mode.
+    ap_ch: Option<ch::Runner<'a, MTU>>,
     bus: Bus<PWR, SPI>,
+    /// Detected in `init`.
+    chip: Chip,
 
     ioctl_state: &'a IoctlState,
     ioctl_id: u16,
+    /// The last ioctl sent was issued by the runner itself, so its response is not for `Control`.
+    runner_ioctl: bool,
     sdpcm_seq: u8,
     sdpcm_seq_max: u8,
 
     events: &'a Events,
+    /// Event received by `rx`, waiting to be published.
+    pending_event: Option<events::Message>,
+
+    bt: Option<BtRunner<'a>>,
 
     #[cfg(feature = "firmware-logs")]
     log: LogState,
@@ -56,74 +73,135 @@ where
 {
     pub(crate) fn new(
         ch: ch::Runner<'a, MTU>,
+        ap_ch: Option<ch::Runner<'a, MTU>>,
         bus: Bus<PWR, SPI>,
         ioctl_state: &'a IoctlState,
         events: &'a Events,
+        bt: Option<BtRunner<'a>>,
     ) -> Self {
         Self {
             ch,
+            ap_ch,
             bus,
+            chip: Chip::CYW43439,
             ioctl_state,
             ioctl_id: 0,
+            runner_ioctl: false,
             sdpcm_seq: 0,
             sdpcm_seq_max: 1,
             events,
+            pending_event: None,
+            bt,
             #[cfg(feature = "firmware-logs")]
             log: LogState::default(),
         }
     }
 
-    pub(crate) async fn init(&mut self, firmware: &[u8]) {
-        self.bus.init().await;
+    pub(crate) async fn init(&mut self, firmware: &[u8], bt_firmware: Option<&[u8]>) -> Result<(), InitError> {
+        self.bus.init().await?;
 
         // Init ALP (Active Low Power) clock
         self.bus
             .write8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR, BACKPLANE_ALP_AVAIL_REQ)
             .await;
         debug!("waiting for clock...");
-        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & BACKPLANE_ALP_AVAIL == 0 {}
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & BACKPLANE_ALP_AVAIL == 0 {
+            if Instant::now() > deadline {
+                warn!("timed out waiting for ALP clock");
+                return Err(InitError::AlpClock);
+            }
+        }
         debug!("clock ok");
 
         let chip_id = self.bus.bp_read16(0x1800_0000).await;
         debug!("chip ID: {}", chip_id);
+        let Some(chip) = Chip::from_id(chip_id) else {
+            warn!("unsupported chip ID {}", chip_id);
+            return Err(InitError::UnknownChip(chip_id));
+        };
+        self.chip = chip;
 
         // Upload firmware.
-        self.core_disable(Core::WLAN).await;
-        self.core_reset(Core::SOCSRAM).await;
-        self.bus.bp_write32(CHIP.socsram_base_address + 0x10, 3).await;
-        self.bus.bp_write32(CHIP.socsram_base_address + 0x44, 0).await;
+        match chip.arm_core {
+            ArmCore::CortexM3 => {
+                self.core_disable(Core::WLAN, 0).await;
+                self.core_reset(Core::SOCSRAM, 0, 0).await;
+                let socsram = unwrap!(chip.socsram_base_address);
+                self.bus.bp_write32(socsram + 0x10, 3).await;
+                self.bus.bp_write32(socsram + 0x44, 0).await;
+            }
+            ArmCore::CortexR4 => {
+                // Keep the CPU halted while the firmware is loaded into its TCM.
+                self.core_reset(Core::WLAN, AI_IOCTRL_BIT_CPUHALT, AI_IOCTRL_BIT_CPUHALT)
+                    .await;
+            }
+        }
+
+        let ram_addr = chip.atcm_ram_base_address;
 
-        let ram_addr = CHIP.atcm_ram_base_address;
+        // Round up to 4 bytes.
+        let nvram_len = (NVRAM.len() + 3) / 4 * 4;
+        if firmware.len() + nvram_len + 4 > chip.chip_ram_size as usize {
+            warn!(
+                "firmware ({} bytes) and nvram ({} bytes) don't fit in chip RAM",
+                firmware.len(),
+                nvram_len
+            );
+            return Err(InitError::FirmwareUpload);
+        }
 
         debug!("loading fw");
         self.bus.bp_write(ram_addr, firmware).await;
 
         debug!("loading nvram");
-        // Round up to 4 bytes.
-        let nvram_len = (NVRAM.len() + 3) / 4 * 4;
         self.bus
-            .bp_write(ram_addr + CHIP.chip_ram_size - 4 - nvram_len as u32, NVRAM)
+            .bp_write(ram_addr + chip.chip_ram_size - 4 - nvram_len as u32, NVRAM)
             .await;
 
         let nvram_len_words = nvram_len as u32 / 4;
         let nvram_len_magic = (!nvram_len_words << 16) | nvram_len_words;
         self.bus
-            .bp_write32(ram_addr + CHIP.chip_ram_size - 4, nvram_len_magic)
+            .bp_write32(ram_addr + chip.chip_ram_size - 4, nvram_len_magic)
             .await;
 
         // Start core!
         debug!("starting up core...");
-        self.core_reset(Core::WLAN).await;
-        assert!(self.core_is_up(Core::WLAN).await);
+        match chip.arm_core {
+            ArmCore::CortexM3 => self.core_reset(Core::WLAN, 0, 0).await,
+            ArmCore::CortexR4 => {
+                // The CR4 boots from address 0, so point it at the firmware's reset vector.
+                if let Some(reset_vector) = firmware.get(..4) {
+                    let reset_vector = u32::from_le_bytes(reset_vector.try_into().unwrap());
+                    self.bus.bp_write32(0, reset_vector).await;
+                }
+                self.core_reset(Core::WLAN, AI_IOCTRL_BIT_CPUHALT, 0).await;
+            }
+        }
+        if !self.core_is_up(Core::WLAN).await {
+            return Err(InitError::CoreStart);
+        }
 
-        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & 0x80 == 0 {}
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & 0x80 == 0 {
+            if Instant::now() > deadline {
+                warn!("timed out waiting for HT clock");
+                return Err(InitError::HtClock);
+            }
+        }
 
         // "Set up the interrupt mask and enable interrupts"
-        // self.bus.bp_write32(CHIP.sdiod_core_base_address + 0x24, 0xF0).await;
-
-        self.bus
-            .write16(FUNC_BUS, REG_BUS_INTERRUPT_ENABLE, IRQ_F2_PACKET_AVAILABLE)
-            .await;
+        // self.bus.bp_write32(chip.sdiod_core_base_address + 0x24, 0xF0).await;
+
+        let mut irq_enable = IRQ_F2_PACKET_AVAILABLE;
+        if self.bt.is_some() {
+            // The BT core signals activity on the shared buffers through the SDIO core mailbox, which shows up as an F1 interrupt.
+            self.bus
+                .bp_write32(chip.sdiod_core_base_address + SDIO_INT_HOST_MASK, I_HMB_FC_CHANGE)
+                .await;
+            irq_enable |= IRQ_F1_INTR;
+        }
+        self.bus.write16(FUNC_BUS, REG_BUS_INTERRUPT_ENABLE, irq_enable).await;
 
         // "Lower F2 Watermark to avoid DMA Hang in F2 when SD Clock is stopped."
         // Sounds scary...
@@ -133,7 +211,13 @@ where
 
         // wait for wifi startup
         debug!("waiting for wifi init...");
-        while self.bus.read32(FUNC_BUS, REG_BUS_STATUS).await & STATUS_F2_RX_READY == 0 {}
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        while self.bus.read32(FUNC_BUS, REG_BUS_STATUS).await & STATUS_F2_RX_READY == 0 {
+            if Instant::now() > deadline {
+                warn!("timed out waiting for F2 ready");
+                return Err(InitError::F2Ready);
+            }
+        }
 
         // Some random configs related to sleep.
         // These aren't needed if we don't want to sleep the bus.
@@ -166,13 +250,20 @@ where
         self.log_init().await;
 
         debug!("wifi init done");
+
+        if let (Some(bt), Some(bt_firmware)) = (&mut self.bt, bt_firmware) {
+            bt.init_bluetooth(&mut self.bus, &self.chip, bt_firmware).await?;
+        }
+
+        Ok(())
     }
 
     #[cfg(feature = "firmware-logs")]
     async fn log_init(&mut self) {
         // Initialize shared memory for logging.
 
-        let addr = CHIP.atcm_ram_base_address + CHIP.chip_ram_size - 4 - CHIP.socram_srmem_size;
+        let chip = &self.chip;
+        let addr = chip.atcm_ram_base_address + chip.chip_ram_size - 4 - chip.socram_srmem_size;
         let shared_addr = self.bus.bp_read32(addr).await;
         debug!("shared_addr {:08x}", shared_addr);
 
@@ -229,21 +320,46 @@ where
             self.log_read().await;
 
             if self.has_credit() {
-                let ioctl = self.ioctl_state.wait_pending();
-                let tx = self.ch.tx_buf();
+                let ioctl = select(self.ioctl_state.wait_pending(), self.ioctl_state.wait_runner_request());
+                let ap_ch = &mut self.ap_ch;
+                let ap_tx = async {
+                    match ap_ch {
+                        Some(ap_ch) => ap_ch.tx_buf().await,
+                        None => pending().await,
+                    }
+                };
+                let tx = select(self.ch.tx_buf(), ap_tx);
                 let ev = self.bus.wait_for_event();
+                let bt = self.bt.as_ref();
+                let bt_work = async {
+                    match bt {
+                        Some(bt) => bt.wait().await,
+                        None => pending().await,
+                    }
+                };
 
-                match select3(ioctl, tx, ev).await {
-                    Either3::First(PendingIoctl {
-                        buf: iobuf,
-                        kind,
-                        cmd,
-                        iface,
-                    }) => {
-                        self.send_ioctl(kind, cmd, iface, unsafe { &*iobuf }).await;
+                match select4(ioctl, tx, ev, bt_work).await {
+                    Either4::First(Either::First(pending)) => {
+                        self.runner_ioctl = false;
+                        let ioctl_state = self.ioctl_state;
+                        self.send_ioctl(pending.kind, pending.cmd, pending.iface, |buf| {
+                            ioctl_state.copy_request(&pending, buf)
+                        })
+                        .await;
+                        self.check_status(&mut buf).await;
+                    }
+                    Either4::First(Either::Second(request)) => {
+                        match request {
+                            RunnerRequest::ScanAbort => self.abort_scan().await,
+                            RunnerRequest::EventMaskUpdate => self.update_event_mask().await,
+                        }
                         self.check_status(&mut buf).await;
                     }
-                    Either3::Second(packet) => {
+                    Either4::Second(tx) => {
+                        let (iface, packet) = match tx {
+                            Either::First(packet) => (0, packet),
+                            Either::Second(packet) => (1, packet),
+                        };
                         trace!("tx pkt {:02x}", Bytes(&packet[..packet.len().min(48)]));
 
                         let mut buf = [0; 512];
@@ -278,7 +394,7 @@ where
                         let bdc_header = BdcHeader {
                             flags: BDC_VERSION << BDC_VERSION_SHIFT,
                             priority: 0,
-                            flags2: 0,
+                            flags2: iface,
                             data_offset: 0,
                         };
                         trace!("tx {:?}", sdpcm_header);
@@ -295,12 +411,23 @@ where
                         trace!("    {:02x}", Bytes(&buf8[..total_len.min(48)]));
 
                         self.bus.wlan_write(&buf[..(total_len / 4)]).await;
-                        self.ch.tx_done();
+                        match (iface, &mut self.ap_ch) {
+                            (1, Some(ap_ch)) => ap_ch.tx_done(),
+                            _ => self.ch.tx_done(),
+                        }
                         self.check_status(&mut buf).await;
                     }
-                    Either3::Third(()) => {
+                    Either4::Third(()) => {
                         self.handle_irq(&mut buf).await;
                     }
+                    Either4::Fourth(work) => {
+                        if let Some(bt) = &mut self.bt {
+                            match work {
+                                BtWork::Write(packet) => bt.hci_write(&mut self.bus, &packet).await,
+                                BtWork::Read => bt.read_packets(&mut self.bus).await,
+                            }
+                        }
+                    }
                 }
             } else {
                 warn!("TX stalled");
@@ -320,6 +447,12 @@ where
             self.check_status(buf).await;
         }
 
+        if irq & IRQ_F1_INTR != 0 {
+            if let Some(bt) = &mut self.bt {
+                bt.handle_irq(&mut self.bus).await;
+            }
+        }
+
         if irq & IRQ_DATA_UNAVAILABLE != 0 {
             // TODO what should we do here?
             warn!("IRQ DATA_UNAVAILABLE, clearing...");
@@ -338,14 +471,43 @@ where
                 self.bus.wlan_read(buf, len).await;
                 trace!("rx {:02x}", Bytes(&slice8_mut(buf)[..(len as usize).min(48)]));
                 self.rx(&mut slice8_mut(buf)[..len as usize]);
+                if let Some(msg) = self.pending_event.take() {
+                    self.publish_event(msg);
+                }
             } else {
                 break;
             }
         }
     }
 
+    /// Publish an event, dropping the oldest queued one if the subscribers haven't made room.
+    ///
+    /// This never waits: a subscriber may be waiting for an ioctl, or for packets, which the
+    /// runner couldn't process while waiting for it.
+    fn publish_event(&mut self, msg: events::Message) {
+        let stats = &self.events.stats;
+        let Ok(publisher) = self.events.queue.publisher() else {
+            unreachable!("the runner is the only event publisher");
+        };
+
+        match publisher.try_publish(msg) {
+            Ok(()) => stats.delivered.set(stats.delivered.get().wrapping_add(1)),
+            Err(msg) => {
+                warn!("event queue full, dropping the oldest event");
+                stats.dropped.set(stats.dropped.get().wrapping_add(1));
+                publisher.publish_immediate(msg);
+            }
+        }
+    }
+
     fn rx(&mut self, packet: &mut [u8]) {
-        let Some((sdpcm_header, payload)) = SdpcmHeader::parse(packet) else { return };
+        let (sdpcm_header, payload) = match SdpcmHeader::parse(packet) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("invalid SDPCM frame: {:?}", e);
+                return;
+            }
+        };
 
         self.update_credit(&sdpcm_header);
 
@@ -353,27 +515,42 @@ where
 
         match channel {
             CHANNEL_TYPE_CONTROL => {
-                let Some((cdc_header, response)) = CdcHeader::parse(payload) else { return; };
+                let (cdc_header, response) = match CdcHeader::parse(payload) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("invalid CDC frame: {:?}", e);
+                        return;
+                    }
+                };
                 trace!("    {:?}", cdc_header);
 
-                if cdc_header.id == self.ioctl_id {
+                if cdc_header.id == self.ioctl_id && self.runner_ioctl {
+                    trace!("response to runner ioctl, status {}", cdc_header.status as i32);
+                } else if cdc_header.id == self.ioctl_id {
                     if cdc_header.status != 0 {
-                        // TODO: propagate error instead
-                        panic!("IOCTL error {}", cdc_header.status as i32);
+                        let error = Bcme::from(cdc_header.status as i32);
+                        warn!("IOCTL error {} ({:?})", cdc_header.status as i32, error);
+                        self.ioctl_state.ioctl_failed(error);
+                    } else {
+                        self.ioctl_state.ioctl_done(response);
                     }
-
-                    self.ioctl_state.ioctl_done(response);
                 }
             }
             CHANNEL_TYPE_EVENT => {
-                let Some((_, bdc_packet)) = BdcHeader::parse(payload) else {
-                    warn!("BDC event, incomplete header");
-                    return;
+                let (_, bdc_packet) = match BdcHeader::parse(payload) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("invalid BDC event header: {:?}", e);
+                        return;
+                    }
                 };
 
-                let Some((event_packet, evt_data)) = EventPacket::parse(bdc_packet) else {
-                    warn!("BDC event, incomplete data");
-                    return;
+                let (event_packet, evt_data) = match EventPacket::parse(bdc_packet) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("invalid event packet: {:?}", e);
+                        return;
+                    }
                 };
 
                 const ETH_P_LINK_CTL: u16 = 0x886c; // HPNA, wlan link local tunnel, according to linux if_ether.h
@@ -413,39 +590,65 @@ where
                     Bytes(evt_data)
                 );
 
+                self.update_link_state(evt_type, &event_packet.msg);
+                let status = event_packet.msg.status;
+                if evt_type == Event::ESCAN_RESULT && status != EStatus::PARTIAL {
+                    self.events.scan_active.set(false);
+                }
+
                 if self.events.mask.is_enabled(evt_type) {
-                    let status = event_packet.msg.status;
                     let event_payload = match evt_type {
                         Event::ESCAN_RESULT if status == EStatus::PARTIAL => {
-                            let Some((_, bss_info)) = ScanResults::parse(evt_data) else { return };
-                            let Some(bss_info) = BssInfo::parse(bss_info) else { return };
-                            events::Payload::BssInfo(*bss_info)
+                            let result = match ScanResults::parse(evt_data).and_then(|(_, bss)| ScanResult::parse(bss))
+                            {
+                                Ok(result) => result,
+                                Err(e) => {
+                                    warn!("invalid scan result: {:?}", e);
+                                    return;
+                                }
+                            };
+                            events::Payload::ScanResult(result)
                         }
                         Event::ESCAN_RESULT => events::Payload::None,
-                        _ => events::Payload::None,
+                        _ => events::Payload::Data(events::EventData::new(evt_data)),
                     };
 
-                    // this intentionally uses the non-blocking publish immediate
-                    // publish() is a deadlock risk in the current design as awaiting here prevents ioctls
-                    // The `Runner` always yields when accessing the device, so consumers always have a chance to receive the event
-                    // (if they are actively awaiting the queue)
-                    self.events.queue.publish_immediate(events::Message::new(
+                    // Published by `check_status` once we're out of `rx`, since that may need to wait for space.
+                    self.pending_event = Some(events::Message::new(
                         Status {
                             event_type: evt_type,
                             status,
+                            reason: event_packet.msg.reason,
+                            addr: event_packet.msg.addr,
+                            ifidx: event_packet.msg.ifidx,
                         },
                         event_payload,
                     ));
                 }
             }
             CHANNEL_TYPE_DATA => {
-                let Some((_, packet)) = BdcHeader::parse(payload) else { return };
+                // An SDPCM frame with no BDC header only carries a credit update.
+                let Ok((bdc_header, packet)) = BdcHeader::parse(payload) else { return };
                 trace!("rx pkt {:02x}", Bytes(&packet[..packet.len().min(48)]));
 
-                match self.ch.try_rx_buf() {
+                let ch = match (bdc_header.flags2 & BDC_FLAG2_IF_MASK, &mut self.ap_ch) {
+                    (0, _) => &mut self.ch,
+                    (1, Some(ap_ch)) => ap_ch,
+                    (iface, _) => {
+                        warn!("rx pkt for unknown interface {}", iface);
+                        return;
+                    }
+                };
+
+                if packet.len() > MTU {
+                    warn!("rx pkt too long, len={}", packet.len());
+                    return;
+                }
+
+                match ch.try_rx_buf() {
                     Some(buf) => {
                         buf[..packet.len()].copy_from_slice(packet);
-                        self.ch.rx_done(packet.len())
+                        ch.rx_done(packet.len())
                     }
                     None => warn!("failed to push rxd packet to the channel."),
                 }
@@ -454,6 +657,40 @@ where
         }
     }
 
+    /// Track the station link from firmware events, so it goes down when the AP goes away.
+    fn update_link_state(&mut self, evt_type: Event, msg: &EventMessage) {
+        let link = &self.events.link;
+        if !link.joined.get() || msg.ifidx != 0 {
+            return;
+        }
+
+        let reason = match evt_type {
+            Event::LINK if msg.flags & 1 != 0 => {
+                if link.down_reason.take().is_some() {
+                    debug!("link up again");
+                    self.ch.set_link_state(LinkState::Up);
+                }
+                return;
+            }
+            Event::LINK => DisconnectReason::LinkDown { reason: msg.reason },
+            Event::DEAUTH_IND => DisconnectReason::Deauth {
+                reason: msg.reason as u16,
+            },
+            Event::DISASSOC_IND => DisconnectReason::Disassoc {
+                reason: msg.reason as u16,
+            },
+            Event::BCNLOST_MSG => DisconnectReason::BeaconLost,
+            _ => return,
+        };
+
+        // Only report the first reason, the firmware usually follows up with a LINK down.
+        if link.down_reason.get().is_none() {
+            warn!("link down: {:?}", reason);
+            link.down_reason.set(Some(reason));
+            self.ch.set_link_state(LinkState::Down);
+        }
+    }
+
     fn update_credit(&mut self, sdpcm_header: &SdpcmHeader) {
         if sdpcm_header.channel_and_flags & 0xf < 3 {
             let mut sdpcm_seq_max = sdpcm_header.bus_data_credit;
@@ -468,11 +705,45 @@ where
         self.sdpcm_seq != self.sdpcm_seq_max && self.sdpcm_seq_max.wrapping_sub(self.sdpcm_seq) & 0x80 == 0
     }
 
-    async fn send_ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, data: &[u8]) {
+    /// Abort the running escan, on behalf of a dropped `Scanner`.
+    async fn abort_scan(&mut self) {
+        const NAME: &[u8] = b"escan\0";
+
+        debug!("aborting scan");
+        let params = ScanParams::new(ESCAN_ACTION_ABORT).to_bytes();
+
+        self.runner_ioctl = true;
+        self.send_ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, |buf| {
+            buf[..NAME.len()].copy_from_slice(NAME);
+            buf[NAME.len()..][..params.len()].copy_from_slice(&params);
+            NAME.len() + params.len()
+        })
+        .await;
+    }
+
+    /// Update the event mask in the firmware, on behalf of an `EventStream`.
+    async fn update_event_mask(&mut self) {
+        const NAME: &[u8] = b"bsscfg:event_msgs\0";
+
+        let mask = self.events.mask.firmware_mask().to_bytes();
+        debug!("updating event mask {:02x}", Bytes(&mask));
+
+        self.runner_ioctl = true;
+        self.send_ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, |buf| {
+            buf[..NAME.len()].copy_from_slice(NAME);
+            buf[NAME.len()..][..mask.len()].copy_from_slice(&mask);
+            NAME.len() + mask.len()
+        })
+        .await;
+    }
+
+    /// Send an ioctl. `write_data` fills in the request data and returns its length.
+    async fn send_ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, write_data: impl FnOnce(&mut [u8]) -> usize) {
         let mut buf = [0; 512];
         let buf8 = slice8_mut(&mut buf);
 
-        let total_len = SdpcmHeader::SIZE + CdcHeader::SIZE + data.len();
+        let data_len = write_data(&mut buf8[SdpcmHeader::SIZE + CdcHeader::SIZE..]);
+        let total_len = SdpcmHeader::SIZE + CdcHeader::SIZE + data_len;
 
         let sdpcm_seq = self.sdpcm_seq;
         self.sdpcm_seq = self.sdpcm_seq.wrapping_add(1);
@@ -492,7 +763,7 @@ where
 
         let cdc_header = CdcHeader {
             cmd: cmd,
-            len: data.len() as _,
+            len: data_len as _,
             flags: kind as u16 | (iface as u16) << 12,
             id: self.ioctl_id,
             status: 0,
@@ -502,7 +773,6 @@ where
 
         buf8[0..SdpcmHeader::SIZE].copy_from_slice(&sdpcm_header.to_bytes());
         buf8[SdpcmHeader::SIZE..][..CdcHeader::SIZE].copy_from_slice(&cdc_header.to_bytes());
-        buf8[SdpcmHeader::SIZE + CdcHeader::SIZE..][..data.len()].copy_from_slice(data);
 
         let total_len = (total_len + 3) & !3; // round up to 4byte
 
@@ -511,8 +781,14 @@ where
         self.bus.wlan_write(&buf[..total_len / 4]).await;
     }
 
-    async fn core_disable(&mut self, core: Core) {
-        let base = core.base_addr();
+    /// The chip detected during init.
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Put `core` in reset, with `ioctrl` as its IOCTRL bits.
+    async fn core_disable(&mut self, core: Core, ioctrl: u8) {
+        let base = core.base_addr(&self.chip);
 
         // Dummy read?
         let _ = self.bus.bp_read8(base + AI_RESETCTRL_OFFSET).await;
@@ -523,10 +799,10 @@ where
             return;
         }
 
-        self.bus.bp_write8(base + AI_IOCTRL_OFFSET, 0).await;
+        self.bus.bp_write8(base + AI_IOCTRL_OFFSET, ioctrl).await;
         let _ = self.bus.bp_read8(base + AI_IOCTRL_OFFSET).await;
 
-        block_for(Duration::from_millis(1));
+        Timer::after(Duration::from_millis(1)).await;
 
         self.bus
             .bp_write8(base + AI_RESETCTRL_OFFSET, AI_RESETCTRL_BIT_RESET)
@@ -534,12 +810,17 @@ where
         let _ = self.bus.bp_read8(base + AI_RESETCTRL_OFFSET).await;
     }
 
-    async fn core_reset(&mut self, core: Core) {
-        self.core_disable(core).await;
+    /// Reset `core`, with `prereset` as its IOCTRL bits while it is held in reset and `ioctrl`
+    /// once it is out of reset.
+    async fn core_reset(&mut self, core: Core, prereset: u8, ioctrl: u8) {
+        self.core_disable(core, prereset).await;
 
-        let base = core.base_addr();
+        let base = core.base_addr(&self.chip);
         self.bus
-            .bp_write8(base + AI_IOCTRL_OFFSET, AI_IOCTRL_BIT_FGC | AI_IOCTRL_BIT_CLOCK_EN)
+            .bp_write8(
+                base + AI_IOCTRL_OFFSET,
+                ioctrl | AI_IOCTRL_BIT_FGC | AI_IOCTRL_BIT_CLOCK_EN,
+            )
             .await;
         let _ = self.bus.bp_read8(base + AI_IOCTRL_OFFSET).await;
 
@@ -548,7 +829,7 @@ where
         Timer::after(Duration::from_millis(1)).await;
 
         self.bus
-            .bp_write8(base + AI_IOCTRL_OFFSET, AI_IOCTRL_BIT_CLOCK_EN)
+            .bp_write8(base + AI_IOCTRL_OFFSET, ioctrl | AI_IOCTRL_BIT_CLOCK_EN)
             .await;
         let _ = self.bus.bp_read8(base + AI_IOCTRL_OFFSET).await;
 
@@ -556,7 +837,7 @@ where
     }
 
     async fn core_is_up(&mut self, core: Core) -> bool {
-        let base = core.base_addr();
+        let base = core.base_addr(&self.chip);
 
         let io = self.bus.bp_read8(base + AI_IOCTRL_OFFSET).await;
         if io & (AI_IOCTRL_BIT_FGC | AI_IOCTRL_BIT_CLOCK_EN) != AI_IOCTRL_BIT_CLOCK_EN {
@@ -573,3 +854,90 @@ where
         true
     }
 }
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::tests::with_sim;
+    use crate::sim::{block_on, Sim, SimEvent};
+    use crate::State;
+
+    #[test]
+    fn event_stats_count_dropped_events() {
+        with_sim(Sim::new(), |_net, control, sim| async move {
+            // Held, but not read.
+            let mut stream = control.subscribe_events(&[Event::LINK]).unwrap();
+            let before = control.event_stats();
+
+            for _ in 0..events::EVENT_QUEUE_DEPTH + 2 {
+                sim.push_event(SimEvent::new(Event::LINK, 0));
+            }
+            sim.push_packet(0, &[0xff; 64]);
+            Timer::after(Duration::from_millis(10)).await;
+
+            // The runner doesn't wait for the stream: it drops the oldest events, and keeps
+            // reading frames from the chip.
+            assert_eq!(sim.pending_frames(), 0);
+            let stats = control.event_stats();
+            assert_eq!(stats.delivered - before.delivered, events::EVENT_QUEUE_DEPTH as u32);
+            assert_eq!(stats.dropped - before.dropped, 2);
+
+            assert_eq!(stream.next().await.event_type, Event::LINK);
+            assert_eq!(stream.lagged(), 2);
+
+            // Once there's room again, events are delivered without dropping any.
+            sim.push_event(SimEvent::new(Event::LINK, 0));
+            Timer::after(Duration::from_millis(10)).await;
+            let after = control.event_stats();
+            assert_eq!(after.delivered - stats.delivered, 1);
+            assert_eq!(after.dropped, stats.dropped);
+        });
+    }
+
+    #[test]
+    fn detect_chip() {
+        let firmware = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+        for chip in [Chip::CYW43439, Chip::CYW43455] {
+            let sim = Sim::with_chip_id(chip.id);
+            let mut state = State::new();
+            let (_net, _control, runner) = block_on(crate::new(&mut state, sim.pwr(), sim.bus(), &firmware)).unwrap();
+            assert_eq!(runner.chip(), chip);
+            assert_eq!(sim.read_backplane(chip.atcm_ram_base_address, 8), firmware);
+        }
+
+        // The CR4 boots from address 0, which holds the firmware's reset vector instead of the
+        // firmware itself.
+        let sim = Sim::with_chip_id(Chip::CYW43455.id);
+        let mut state = State::new();
+        block_on(crate::new(&mut state, sim.pwr(), sim.bus(), &firmware)).unwrap();
+        assert_eq!(sim.read_backplane(0, 8), [0x11, 0x22, 0x33, 0x44, 0, 0, 0, 0]);
+
+        let sim = Sim::with_chip_id(0x1234);
+        let mut state = State::new();
+        let err = block_on(crate::new(&mut state, sim.pwr(), sim.bus(), &firmware)).err();
+        assert_eq!(err, Some(InitError::UnknownChip(0x1234)));
+    }
+
+    #[test]
+    fn init_failures() {
+        for stage in [
+            InitError::AlpClock,
+            InitError::CoreStart,
+            InitError::HtClock,
+            InitError::F2Ready,
+        ] {
+            let sim = Sim::new();
+            sim.stall_init(stage);
+            let mut state = State::new();
+            let err = block_on(crate::new(&mut state, sim.pwr(), sim.bus(), &[0; 4])).err();
+            assert_eq!(err, Some(stage));
+        }
+
+        let sim = Sim::new();
+        let firmware = std::vec![0; Chip::CYW43439.chip_ram_size as usize];
+        let mut state = State::new();
+        let err = block_on(crate::new(&mut state, sim.pwr(), sim.bus(), &firmware)).err();
+        assert_eq!(err, Some(InitError::FirmwareUpload));
+    }
+}
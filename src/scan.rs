@@ -0,0 +1,720 @@
+use core::ops::Deref;
+
+use embassy_time::Instant;
+
+use crate::consts::*;
+use crate::control::{JoinAuth, Scanner};
+use crate::fmt::Bytes;
+use crate::structs::{BssInfo, ParseError};
+
+/// Maximum length of the IEs kept for each scan result. Longer IE blobs are truncated.
+pub const MAX_IE_LEN: usize = 512;
+
+const IE_RSN: u8 = 48;
+const IE_VENDOR: u8 = 221;
+
+const CAPABILITY_PRIVACY: u16 = 1 << 4;
+
+const RSN_OUI: [u8; 3] = [0x00, 0x0f, 0xac];
+const WPA_OUI: [u8; 3] = [0x00, 0x50, 0xf2];
+const WPA_OUI_TYPE: u8 = 1;
+
+const AKM_8021X: u8 = 1;
+const AKM_PSK: u8 = 2;
+const AKM_FT_8021X: u8 = 3;
+const AKM_FT_PSK: u8 = 4;
+const AKM_8021X_SHA256: u8 = 5;
+const AKM_PSK_SHA256: u8 = 6;
+const AKM_SAE: u8 = 8;
+const AKM_FT_SAE: u8 = 9;
+
+const RSN_CAP_MFP_REQUIRED: u16 = 1 << 6;
+const RSN_CAP_MFP_CAPABLE: u16 = 1 << 7;
+
+/// A network found by a scan.
+///
+/// Dereferences to the [`BssInfo`] reported by the firmware, and additionally holds
+/// the information elements from the beacon or probe response.
+#[derive(Clone, Copy)]
+pub struct ScanResult {
+    info: BssInfo,
+    ie_len: usize,
+    ies: [u8; MAX_IE_LEN],
+}
+
+impl ScanResult {
+    /// Parse a `wl_bss_info_t` followed by its IEs.
+    pub(crate) fn parse(packet: &[u8]) -> Result<Self, ParseError> {
+        let info = BssInfo::parse(packet)?;
+
+        let ie_offset = info.ie_offset as usize;
+        let ie_length = info.ie_length as usize;
+        let end = (info.length as usize).min(packet.len());
+        let ies = packet.get(ie_offset..end).unwrap_or(&[]);
+        let ies = &ies[..ie_length.min(ies.len())];
+        if ies.len() > MAX_IE_LEN {
+            debug!("scan result IEs truncated from {} to {}", ies.len(), MAX_IE_LEN);
+        }
+
+        let mut res = Self {
+            info,
+            ie_len: ies.len().min(MAX_IE_LEN),
+            ies: [0; MAX_IE_LEN],
+        };
+        res.ies[..res.ie_len].copy_from_slice(&ies[..res.ie_len]);
+        Ok(res)
+    }
+
+    /// The raw information elements.
+    pub fn ies(&self) -> &[u8] {
+        &self.ies[..self.ie_len]
+    }
+
+    /// Iterate over the information elements, as `(id, data)` pairs.
+    pub fn iter_ies(&self) -> IeIter<'_> {
+        IeIter { data: self.ies() }
+    }
+
+    /// Summary of the security the network uses, decoded from the RSN and WPA IEs.
+    pub fn security(&self) -> SecuritySummary {
+        let mut res = SecuritySummary {
+            privacy: self.info.capability & CAPABILITY_PRIVACY != 0,
+            ..Default::default()
+        };
+
+        for (id, data) in self.iter_ies() {
+            match id {
+                IE_RSN => parse_rsn(data, &mut res),
+                IE_VENDOR if data.len() >= 4 && data[0..3] == WPA_OUI && data[3] == WPA_OUI_TYPE => {
+                    parse_wpa(&data[4..], &mut res)
+                }
+                _ => {}
+            }
+        }
+
+        res
+    }
+}
+
+impl Deref for ScanResult {
+    type Target = BssInfo;
+
+    fn deref(&self) -> &BssInfo {
+        &self.info
+    }
+}
+
+/// Iterator over information elements.
+pub struct IeIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for IeIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&id, rest) = self.data.split_first()?;
+        let (&len, rest) = rest.split_first()?;
+        let Some(data) = rest.get(..len as usize) else {
+            self.data = &[];
+            return None;
+        };
+        self.data = &rest[len as usize..];
+        Some((id, data))
+    }
+}
+
+/// Security used by a network, as advertised in its beacon.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SecuritySummary {
+    /// The privacy bit is set, so some kind of encryption is used (possibly WEP).
+    pub privacy: bool,
+    /// WPA (the vendor IE, not RSN) is offered.
+    pub wpa: bool,
+    /// WPA2-Personal (RSN with PSK) is offered.
+    pub wpa2: bool,
+    /// WPA3-Personal (RSN with SAE) is offered.
+    pub wpa3: bool,
+    /// WPA-Enterprise (802.1X) is offered.
+    pub enterprise: bool,
+    /// Management frame protection is supported.
+    pub mfp_capable: bool,
+    /// Management frame protection is required.
+    pub mfp_required: bool,
+}
+
+impl SecuritySummary {
+    /// The network is open, with no encryption.
+    pub fn is_open(&self) -> bool {
+        !self.privacy && !self.wpa && !self.wpa2 && !self.wpa3 && !self.enterprise
+    }
+
+    /// The mode to pass to [`crate::Control::join`] for this network, if it is supported.
+    pub fn join_auth(&self) -> Option<JoinAuth> {
+        match (self.is_open(), self.wpa2, self.wpa3) {
+            (true, _, _) => Some(JoinAuth::Open),
+            (_, true, true) => Some(JoinAuth::Wpa2Wpa3),
+            (_, false, true) => Some(JoinAuth::Wpa3),
+            (_, true, false) => Some(JoinAuth::Wpa2),
+            _ => None,
+        }
+    }
+}
+
+/// Skip the group cipher and the pairwise cipher list, and return the AKM suites and what follows them.
+///
+/// The lists are optional at the end of the IE, so if it ends before the AKM suite count the AKM
+/// list is empty. Returns `None` if a list is truncated.
+fn akm_suites(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    // group cipher suite
+    let data = data.get(4..)?;
+    if data.is_empty() {
+        return Some((&[], &[]));
+    }
+    let pairwise_count = u16::from_le_bytes(data.get(0..2)?.try_into().unwrap()) as usize;
+    let data = data.get(2 + 4 * pairwise_count..)?;
+    if data.is_empty() {
+        return Some((&[], &[]));
+    }
+    let akm_count = u16::from_le_bytes(data.get(0..2)?.try_into().unwrap()) as usize;
+    let akms = data.get(2..2 + 4 * akm_count)?;
+    Some((akms, &data[2 + 4 * akm_count..]))
+}
+
+fn parse_rsn(data: &[u8], res: &mut SecuritySummary) {
+    // version
+    let Some(data) = data.get(2..) else { return };
+    let Some((akms, rest)) = akm_suites(data) else {
+        debug!("malformed RSN IE");
+        return;
+    };
+    if akms.is_empty() {
+        // No AKM suite list, so the AKM defaults to 802.1X.
+        res.enterprise = true;
+        return;
+    }
+
+    for akm in akms.chunks_exact(4) {
+        if akm[0..3] != RSN_OUI {
+            continue;
+        }
+        match akm[3] {
+            AKM_PSK | AKM_FT_PSK | AKM_PSK_SHA256 => res.wpa2 = true,
+            AKM_SAE | AKM_FT_SAE => res.wpa3 = true,
+            AKM_8021X | AKM_FT_8021X | AKM_8021X_SHA256 => res.enterprise = true,
+            _ => {}
+        }
+    }
+
+    if let Some(caps) = rest.get(0..2) {
+        let caps = u16::from_le_bytes(caps.try_into().unwrap());
+        res.mfp_capable |= caps & RSN_CAP_MFP_CAPABLE != 0;
+        res.mfp_required |= caps & RSN_CAP_MFP_REQUIRED != 0;
+    }
+}
+
+fn parse_wpa(data: &[u8], res: &mut SecuritySummary) {
+    res.wpa = true;
+
+    // version
+    let Some(data) = data.get(2..) else { return };
+    let Some((akms, _)) = akm_suites(data) else { return };
+    for akm in akms.chunks_exact(4) {
+        if akm[0..3] == WPA_OUI && akm[3] == AKM_8021X {
+            res.enterprise = true;
+        }
+    }
+}
+
+/// Frequency band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Band {
+    Band2G4,
+    Band5G,
+}
+
+/// Channel bandwidth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Bandwidth {
+    Mhz10,
+    Mhz20,
+    Mhz40,
+    Unknown,
+}
+
+impl BssInfo {
+    /// The SSID, without the trailing zeros.
+    pub fn ssid(&self) -> &[u8] {
+        &self.ssid[..(self.ssid_len as usize).min(32)]
+    }
+
+    /// Received signal strength, in dBm.
+    pub fn rssi(&self) -> i16 {
+        self.rssi
+    }
+
+    /// Noise floor, in dBm.
+    pub fn noise(&self) -> i8 {
+        self.phy_noise
+    }
+
+    /// Signal to noise ratio, in dB.
+    pub fn snr(&self) -> i16 {
+        self.snr
+    }
+
+    /// Primary (control) channel number.
+    pub fn channel(&self) -> u8 {
+        if self.n_cap != 0 && self.ctl_ch != 0 {
+            self.ctl_ch
+        } else {
+            (self.chanspec & 0xff) as u8
+        }
+    }
+
+    pub fn band(&self) -> Band {
+        if self.chanspec as u32 & CHANSPEC_BAND_MASK == CHANSPEC_BAND_5G {
+            Band::Band5G
+        } else {
+            Band::Band2G4
+        }
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        match self.chanspec as u32 & CHANSPEC_BW_MASK {
+            CHANSPEC_BW_10 => Bandwidth::Mhz10,
+            CHANSPEC_BW_20 => Bandwidth::Mhz20,
+            CHANSPEC_BW_40 => Bandwidth::Mhz40,
+            _ => Bandwidth::Unknown,
+        }
+    }
+
+    /// Supported rates, in units of 500kbps. The high bit marks basic rates.
+    pub fn rates(&self) -> &[u8] {
+        &self.rates[..(self.rateset_count as usize).min(16)]
+    }
+}
+
+/// An entry in a [`ScanTable`].
+#[derive(Clone, Copy)]
+pub struct ScanEntry {
+    /// The result with the strongest RSSI seen for this BSSID.
+    pub result: ScanResult,
+    /// When this BSSID was first reported.
+    pub first_seen: Instant,
+    /// When this BSSID was last reported.
+    pub last_seen: Instant,
+}
+
+/// Fixed-capacity table of scan results, deduplicated by BSSID.
+///
+/// The firmware reports each BSS several times during a scan. The table keeps one entry
+/// per BSSID, with the strongest result and the time it was last seen. When the table is
+/// full, new networks replace the weakest entry if they are stronger. Among equally weak
+/// entries, the one seen least recently is replaced.
+///
+/// Each entry holds a full [`ScanResult`] including its IEs, so keep `N` small.
+///
+/// ```ignore
+/// let mut table = ScanTable::<8>::new();
+/// let mut scanner = control.scan().await?;
+/// table.collect(&mut scanner).await;
+/// for entry in table.sorted() {
+///     info!("{:x} {}", entry.result.bssid, entry.result.rssi());
+/// }
+/// ```
+pub struct ScanTable<const N: usize> {
+    entries: heapless::Vec<ScanEntry, N>,
+}
+
+impl<const N: usize> ScanTable<N> {
+    pub fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    /// Add a scan result, merging it with the existing entry for its BSSID.
+    pub fn insert(&mut self, result: ScanResult) {
+        let now = Instant::now();
+
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.result.bssid == result.bssid) {
+            if result.rssi() > entry.result.rssi() {
+                entry.result = result;
+            }
+            entry.last_seen = now;
+            return;
+        }
+
+        let entry = ScanEntry {
+            result,
+            first_seen: now,
+            last_seen: now,
+        };
+        if let Err(entry) = self.entries.push(entry) {
+            let weakest = self.entries.iter_mut().min_by_key(|e| (e.result.rssi(), e.last_seen));
+            match weakest {
+                Some(weakest) if weakest.result.rssi() < entry.result.rssi() => *weakest = entry,
+                _ => debug!("scan table full, dropping {:02x}", Bytes(&entry.result.bssid)),
+            }
+        }
+    }
+
+    /// Add all results from `scanner` until the scan completes.
+    pub async fn collect(&mut self, scanner: &mut Scanner<'_>) {
+        while let Some(result) = scanner.next().await {
+            self.insert(result);
+        }
+    }
+
+    /// Entries sorted by RSSI, strongest first.
+    pub fn sorted(&mut self) -> &[ScanEntry] {
+        self.entries
+            .sort_unstable_by_key(|e| core::cmp::Reverse(e.result.rssi()));
+        &self.entries
+    }
+
+    /// Entries, in no particular order.
+    pub fn entries(&self) -> &[ScanEntry] {
+        &self.entries
+    }
+
+    /// Remove entries not seen since `since`.
+    pub fn retain_seen_since(&mut self, since: Instant) {
+        self.entries.retain(|e| e.last_seen >= since);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<const N: usize> Default for ScanTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    const RSN_PSK: [u8; 20] = [
+        1, 0, // version
+        0x00, 0x0f, 0xac, 4, // group cipher: CCMP
+        1, 0, 0x00, 0x0f, 0xac, 4, // pairwise ciphers: CCMP
+        1, 0, 0x00, 0x0f, 0xac, 2, // AKMs: PSK
+        0, 0, // capabilities
+    ];
+
+    fn ie(id: u8, data: &[u8]) -> Vec<u8> {
+        let mut res = Vec::from([id, data.len() as u8]);
+        res.extend_from_slice(data);
+        res
+    }
+
+    fn scan_result(capability: u16, ies: &[u8]) -> ScanResult {
+        let mut info = BssInfo::from_bytes(&[0; BssInfo::SIZE]);
+        info.version = 109;
+        info.length = (BssInfo::SIZE + ies.len()) as u32;
+        info.capability = capability;
+        info.ie_offset = BssInfo::SIZE as u16;
+        info.ie_length = ies.len() as u32;
+
+        let mut packet = Vec::from(info.to_bytes());
+        packet.extend_from_slice(ies);
+        ScanResult::parse(&packet).unwrap()
+    }
+
+    fn bss(id: u8, rssi: i16) -> ScanResult {
+        let mut info = BssInfo::from_bytes(&[0; BssInfo::SIZE]);
+        info.length = BssInfo::SIZE as u32;
+        info.bssid = [0, 0x11, 0x22, 0x33, 0x44, id];
+        info.rssi = rssi;
+        ScanResult::parse(&info.to_bytes()).unwrap()
+    }
+
+    fn ids<const N: usize>(table: &mut ScanTable<N>) -> Vec<(u8, i16)> {
+        table
+            .sorted()
+            .iter()
+            .map(|e| (e.result.bssid[5], e.result.rssi()))
+            .collect()
+    }
+
+    fn security(ies: &[u8]) -> SecuritySummary {
+        scan_result(CAPABILITY_PRIVACY, ies).security()
+    }
+
+    #[test]
+    fn iter_ies() {
+        let mut ies = ie(0, b"ssid");
+        ies.extend(ie(3, &[]));
+        ies.extend(ie(IE_VENDOR, &[1, 2, 3]));
+        let res = scan_result(0, &ies);
+
+        let mut iter = res.iter_ies();
+        assert_eq!(iter.next(), Some((0, &b"ssid"[..])));
+        assert_eq!(iter.next(), Some((3, &[][..])));
+        assert_eq!(iter.next(), Some((IE_VENDOR, &[1, 2, 3][..])));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iter_ies_truncated() {
+        // The last IE claims more data than there is.
+        let mut ies = ie(0, b"ssid");
+        ies.extend([IE_RSN, 20, 1, 0]);
+        let res = scan_result(0, &ies);
+        assert_eq!(res.iter_ies().collect::<Vec<_>>(), [(0, &b"ssid"[..])]);
+
+        // Only the id of the last IE.
+        let mut ies = ie(0, b"ssid");
+        ies.push(IE_RSN);
+        assert_eq!(scan_result(0, &ies).iter_ies().count(), 1);
+    }
+
+    #[test]
+    fn ies_clamped_to_packet() {
+        let ies = ie(0, b"ssid");
+        let mut info = BssInfo::from_bytes(&[0; BssInfo::SIZE]);
+        info.length = 1000;
+        info.ie_offset = BssInfo::SIZE as u16;
+        info.ie_length = 1000;
+        let mut packet = Vec::from(info.to_bytes());
+        packet.extend_from_slice(&ies);
+        assert_eq!(ScanResult::parse(&packet).unwrap().ies(), &ies[..]);
+
+        // IE offset past the end of the packet.
+        info.ie_offset = 2000;
+        assert!(ScanResult::parse(&info.to_bytes()).unwrap().ies().is_empty());
+
+        assert_eq!(ScanResult::parse(&packet[..10]).err(), Some(ParseError::TooShort));
+    }
+
+    #[test]
+    fn security_open_and_wep() {
+        let open = scan_result(0, &ie(0, b"ssid")).security();
+        assert!(open.is_open());
+        assert_eq!(open.join_auth(), Some(JoinAuth::Open));
+
+        let wep = security(&[]);
+        assert!(wep.privacy && !wep.is_open());
+        assert_eq!(wep.join_auth(), None);
+    }
+
+    #[test]
+    fn security_wpa2() {
+        let res = security(&ie(IE_RSN, &RSN_PSK));
+        assert!(res.wpa2 && !res.wpa3 && !res.enterprise && !res.mfp_capable);
+        assert_eq!(res.join_auth(), Some(JoinAuth::Wpa2));
+    }
+
+    #[test]
+    fn security_wpa3_and_transition() {
+        let mut rsn = RSN_PSK;
+        rsn[17] = AKM_SAE;
+        rsn[18] = (RSN_CAP_MFP_CAPABLE | RSN_CAP_MFP_REQUIRED) as u8;
+        let res = security(&ie(IE_RSN, &rsn));
+        assert!(res.wpa3 && !res.wpa2 && res.mfp_capable && res.mfp_required);
+        assert_eq!(res.join_auth(), Some(JoinAuth::Wpa3));
+
+        let transition = [
+            1,
+            0, // version
+            0x00,
+            0x0f,
+            0xac,
+            4, // group cipher
+            1,
+            0,
+            0x00,
+            0x0f,
+            0xac,
+            4, // pairwise ciphers
+            2,
+            0,
+            0x00,
+            0x0f,
+            0xac,
+            2,
+            0x00,
+            0x0f,
+            0xac,
+            8, // AKMs: PSK, SAE
+            RSN_CAP_MFP_CAPABLE as u8,
+            0, // capabilities
+        ];
+        let res = security(&ie(IE_RSN, &transition));
+        assert!(res.wpa2 && res.wpa3 && res.mfp_capable && !res.mfp_required);
+        assert_eq!(res.join_auth(), Some(JoinAuth::Wpa2Wpa3));
+    }
+
+    #[test]
+    fn security_enterprise() {
+        let mut rsn = RSN_PSK;
+        rsn[17] = AKM_8021X;
+        let res = security(&ie(IE_RSN, &rsn));
+        assert!(res.enterprise && !res.wpa2);
+        assert_eq!(res.join_auth(), None);
+
+        // Without the AKM list, the AKM defaults to 802.1X.
+        assert!(security(&ie(IE_RSN, &RSN_PSK[..6])).enterprise);
+        assert!(security(&ie(IE_RSN, &RSN_PSK[..12])).enterprise);
+    }
+
+    #[test]
+    fn security_wpa() {
+        let wpa = [
+            0x00,
+            0x50,
+            0xf2,
+            WPA_OUI_TYPE, // vendor
+            1,
+            0, // version
+            0x00,
+            0x50,
+            0xf2,
+            2, // group cipher: TKIP
+            1,
+            0,
+            0x00,
+            0x50,
+            0xf2,
+            2, // pairwise ciphers: TKIP
+            1,
+            0,
+            0x00,
+            0x50,
+            0xf2,
+            2, // AKMs: PSK
+        ];
+        let res = security(&ie(IE_VENDOR, &wpa));
+        assert!(res.wpa && !res.wpa2 && !res.enterprise);
+
+        // Other vendor IEs are ignored.
+        let mut other = wpa;
+        other[3] = 4;
+        assert!(!security(&ie(IE_VENDOR, &other)).wpa);
+    }
+
+    #[test]
+    fn security_malformed_rsn() {
+        // AKM count larger than the list.
+        let mut rsn = RSN_PSK;
+        rsn[12] = 5;
+        assert_eq!(security(&ie(IE_RSN, &rsn)), security(&[]));
+
+        // Pairwise cipher list cut short.
+        assert_eq!(security(&ie(IE_RSN, &RSN_PSK[..9])), security(&[]));
+
+        // Too short for the version or group cipher.
+        assert_eq!(security(&ie(IE_RSN, &[1])), security(&[]));
+        assert_eq!(security(&ie(IE_RSN, &RSN_PSK[..4])), security(&[]));
+
+        // Truncated capabilities are ignored.
+        let res = security(&ie(IE_RSN, &RSN_PSK[..19]));
+        assert!(res.wpa2 && !res.mfp_capable);
+    }
+
+    fn entry<const N: usize>(table: &ScanTable<N>, id: u8) -> ScanEntry {
+        *table.entries().iter().find(|e| e.result.bssid[5] == id).unwrap()
+    }
+
+    fn tick() {
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    #[test]
+    fn scan_table_dedupes_by_bssid() {
+        let mut table = ScanTable::<4>::new();
+        table.insert(bss(1, -60));
+        let first = entry(&table, 1);
+        assert_eq!(first.first_seen, first.last_seen);
+
+        tick();
+        table.insert(bss(2, -70));
+        table.insert(bss(1, -50));
+        assert_eq!(table.len(), 2);
+        assert_eq!(ids(&mut table), [(1, -50), (2, -70)]);
+        let stronger = entry(&table, 1);
+        assert_eq!(stronger.first_seen, first.first_seen);
+        assert!(stronger.last_seen > first.last_seen);
+
+        // A weaker report updates the time seen, but keeps the stronger result.
+        tick();
+        table.insert(bss(1, -80));
+        assert_eq!(ids(&mut table), [(1, -50), (2, -70)]);
+        let weaker = entry(&table, 1);
+        assert_eq!(weaker.first_seen, first.first_seen);
+        assert!(weaker.last_seen > stronger.last_seen);
+    }
+
+    #[test]
+    fn scan_table_evicts_weakest() {
+        let mut table = ScanTable::<3>::new();
+        table.insert(bss(1, -60));
+        table.insert(bss(2, -80));
+        table.insert(bss(3, -70));
+
+        // Weaker than everything in the table, dropped.
+        table.insert(bss(4, -90));
+        assert_eq!(ids(&mut table), [(1, -60), (3, -70), (2, -80)]);
+
+        // Replaces the weakest entry.
+        table.insert(bss(5, -65));
+        assert_eq!(ids(&mut table), [(1, -60), (5, -65), (3, -70)]);
+
+        assert!(table.entries().iter().all(|e| e.result.bssid[5] != 2));
+        assert!(entry(&table, 5).first_seen >= entry(&table, 3).last_seen);
+
+        // Existing BSSIDs are merged even when full.
+        table.insert(bss(3, -40));
+        assert_eq!(ids(&mut table), [(3, -40), (1, -60), (5, -65)]);
+    }
+
+    #[test]
+    fn scan_table_evicts_oldest_of_weakest() {
+        let mut table = ScanTable::<3>::new();
+        table.insert(bss(1, -70));
+        tick();
+        table.insert(bss(2, -70));
+        tick();
+        table.insert(bss(3, -60));
+        tick();
+
+        // Seen again, so 2 is now the least recently seen of the weakest.
+        table.insert(bss(1, -75));
+        tick();
+        table.insert(bss(4, -65));
+        let mut left: Vec<_> = table.entries().iter().map(|e| e.result.bssid[5]).collect();
+        left.sort();
+        assert_eq!(left, [1, 3, 4]);
+    }
+
+    #[test]
+    fn scan_table_retain_and_clear() {
+        let mut table = ScanTable::<4>::new();
+        table.insert(bss(1, -60));
+        let since = Instant::now() + embassy_time::Duration::from_secs(1);
+        table.retain_seen_since(since);
+        assert!(table.is_empty());
+
+        table.insert(bss(2, -60));
+        table.clear();
+        assert_eq!(table.len(), 0);
+    }
+}
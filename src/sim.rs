@@ -0,0 +1,1317 @@
+//! Software model of the CYW43, for testing the driver on a host without hardware.
+//!
+//! [`Sim`] implements enough of the chip for [`crate::new`], [`crate::Runner`] and
+//! [`crate::Control`] to run against it: the gSPI command words (including the 16-bit swapped
+//! mode used before the bus is configured), the F0 bus registers, the F1 backplane registers
+//! and backplane window, and SDPCM framing on F2 with CDC ioctls, BDC data packets and events.
+//!
+//! Ioctls and iovars are answered from tables that the test can fill, or by a hook installed
+//! with [`Sim::on_ioctl`] that can also queue events, for example to complete a join or a scan.
+//! Bluetooth is not simulated.
+//!
+//! ```ignore
+//! let sim = Sim::new();
+//! sim.on_ioctl(|req| match req.iovar_name() {
+//!     Some("join") => Some(IoctlReply::ok().event(SimEvent::new(Event::SET_SSID, 0))),
+//!     _ => None,
+//! });
+//!
+//! let (net_device, mut control, runner) = cyw43::new(state, sim.pwr(), sim.bus(), &[0; 4]).await?;
+//! // spawn `runner.run()`, then use `control` as usual.
+//! ```
+
+use core::future::{poll_fn, Future};
+use core::task::{Context, Poll, Waker};
+use std::borrow::ToOwned;
+use std::boxed::Box;
+use std::collections::{HashMap, VecDeque};
+use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::task::Wake;
+use std::thread::{self, Thread};
+use std::vec::Vec;
+
+use crate::consts::*;
+use crate::events::Event;
+use crate::ioctl::IoctlType;
+use crate::structs::*;
+use crate::{Chip, Core, InitError, SpiBusCyw43};
+
+/// Largest frame the status register can announce.
+const MAX_FRAME_LEN: usize = 0x7ff;
+
+/// A simulated CYW43. Cloning it gives another handle to the same chip.
+#[derive(Clone)]
+pub struct Sim {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Sim {
+    /// A simulated CYW43439.
+    pub fn new() -> Self {
+        Self::with_chip_id(Chip::CYW43439.id)
+    }
+
+    /// A simulated chip reporting `chip_id`. The memory map isn't modeled, so any ID works.
+    pub fn with_chip_id(chip_id: u16) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState::new(chip_id))),
+        }
+    }
+
+    /// The bus to pass to [`crate::new`].
+    pub fn bus(&self) -> SimBus {
+        SimBus { sim: self.clone() }
+    }
+
+    /// The power pin to pass to [`crate::new`]. Driving it low resets the chip.
+    pub fn pwr(&self) -> SimPwr {
+        SimPwr { sim: self.clone() }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut SimState) -> R) -> R {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    /// MAC address reported in `cur_etheraddr`.
+    pub fn set_mac_addr(&self, addr: [u8; 6]) {
+        self.set_iovar("cur_etheraddr", &addr);
+    }
+
+    /// Value returned when the driver gets the iovar `name`.
+    pub fn set_iovar(&self, name: &str, value: &[u8]) {
+        self.with(|s| s.iovars.insert(name.to_owned(), value.to_vec()));
+    }
+
+    /// The current value of the iovar `name`, as last set by the driver or [`Sim::set_iovar`].
+    pub fn iovar(&self, name: &str) -> Option<Vec<u8>> {
+        self.with(|s| s.iovars.get(name).cloned())
+    }
+
+    /// Value returned when the driver issues the GET ioctl `cmd`.
+    pub fn set_ioctl(&self, cmd: u32, value: &[u8]) {
+        self.with(|s| s.ioctls.insert(cmd, value.to_vec()));
+    }
+
+    /// The current value of the ioctl `cmd`, as last set by the driver or [`Sim::set_ioctl`].
+    pub fn ioctl(&self, cmd: u32) -> Option<Vec<u8>> {
+        self.with(|s| s.ioctls.get(&cmd).cloned())
+    }
+
+    /// Install a hook called for every ioctl before the default handling. Returning `None`
+    /// falls back to the iovar and ioctl tables.
+    pub fn on_ioctl(&self, hook: impl FnMut(&IoctlRequest) -> Option<IoctlReply> + Send + 'static) {
+        self.with(|s| s.hook = Some(Box::new(hook)));
+    }
+
+    /// All ioctls sent by the driver so far, oldest first.
+    pub fn ioctl_log(&self) -> Vec<IoctlRequest> {
+        self.with(|s| s.log.clone())
+    }
+
+    /// Send an event to the driver.
+    pub fn push_event(&self, event: SimEvent) {
+        self.with(|s| s.push_event(&event));
+    }
+
+    /// Send a data packet (an ethernet frame) to the driver, on interface `iface`.
+    pub fn push_packet(&self, iface: u8, packet: &[u8]) {
+        self.with(|s| s.push_packet(iface, packet));
+    }
+
+    /// Send `frame` to the driver as is, without adding an SDPCM header. For testing how the
+    /// driver handles malformed frames. Frames longer than the bus can announce are truncated.
+    pub fn push_raw_frame(&self, frame: &[u8]) {
+        self.with(|s| {
+            s.rx.push_back(frame[..frame.len().min(MAX_FRAME_LEN)].to_vec());
+            s.wake();
+        });
+    }
+
+    /// Number of frames queued for the driver that it hasn't read yet.
+    pub fn pending_frames(&self) -> usize {
+        self.with(|s| s.rx.len())
+    }
+
+    /// Take the data packets sent by the driver so far, with the interface they were sent on.
+    pub fn take_tx_packets(&self) -> Vec<(u8, Vec<u8>)> {
+        self.with(|s| s.tx_packets.drain(..).collect())
+    }
+
+    /// Associate a client to the AP on interface `iface`, and send `ASSOC_IND` for it.
+    ///
+    /// The client is listed in `GET_ASSOCLIST`, and `GET_RSSI` for its address returns `rssi`.
+    pub fn associate(&self, iface: u8, addr: [u8; 6], rssi: i32) {
+        self.with(|s| {
+            s.ap_clients.push((iface, addr, rssi));
+            s.push_event(&SimEvent::new(Event::ASSOC_IND, 0).addr(addr).ifidx(iface));
+        });
+    }
+
+    /// Disassociate a client from the AP on interface `iface`, and send `DISASSOC_IND` for it
+    /// with the 802.11 reason code `reason`.
+    pub fn disassociate(&self, iface: u8, addr: [u8; 6], reason: u16) {
+        self.with(|s| {
+            s.ap_clients.retain(|&(i, a, _)| (i, a) != (iface, addr));
+            let event = SimEvent::new(Event::DISASSOC_IND, 0)
+                .reason(reason as u32)
+                .addr(addr)
+                .ifidx(iface);
+            s.push_event(&event);
+        });
+    }
+
+    /// Make bring-up get stuck at `stage`, so [`crate::new`] fails with it once it times out.
+    ///
+    /// `stage` is one of `AlpClock`, `CoreStart`, `HtClock` and `F2Ready`. Other stages are
+    /// triggered by their inputs instead, for example `FirmwareUpload` by a firmware that is
+    /// too large.
+    pub fn stall_init(&self, stage: InitError) {
+        assert!(matches!(
+            stage,
+            InitError::AlpClock | InitError::CoreStart | InitError::HtClock | InitError::F2Ready
+        ));
+        self.with(|s| s.stall = Some(stage));
+    }
+
+    /// Read the backplane memory, for example to check the uploaded firmware.
+    pub fn read_backplane(&self, addr: u32, len: usize) -> Vec<u8> {
+        self.with(|s| (0..len as u32).map(|i| s.bp_read_byte(addr + i)).collect())
+    }
+}
+
+impl Default for Sim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An ioctl sent by the driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoctlRequest {
+    pub set: bool,
+    pub cmd: u32,
+    pub iface: u32,
+    /// The ioctl buffer. For iovars, this is the NUL-terminated name followed by the value.
+    pub data: Vec<u8>,
+}
+
+impl IoctlRequest {
+    /// The iovar name, for `GET_VAR` and `SET_VAR`.
+    pub fn iovar_name(&self) -> Option<&str> {
+        if self.cmd != IOCTL_CMD_GET_VAR && self.cmd != IOCTL_CMD_SET_VAR {
+            return None;
+        }
+        let end = self.data.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&self.data[..end]).ok()
+    }
+
+    /// The value following the iovar name, for `SET_VAR`.
+    pub fn iovar_value(&self) -> Option<&[u8]> {
+        let name = self.iovar_name()?;
+        Some(&self.data[name.len() + 1..])
+    }
+}
+
+/// The reply to an ioctl, returned by the hook installed with [`Sim::on_ioctl`].
+pub struct IoctlReply {
+    result: Result<Vec<u8>, Bcme>,
+    events: Vec<SimEvent>,
+    respond: bool,
+    /// Pad or truncate the response to the request length, like the firmware does for most ioctls.
+    fit_to_request: bool,
+}
+
+impl IoctlReply {
+    /// Succeed, echoing the request buffer.
+    pub fn ok() -> Self {
+        Self {
+            result: Ok(Vec::new()),
+            events: Vec::new(),
+            respond: true,
+            fit_to_request: true,
+        }
+    }
+
+    /// Succeed with `data` as the response. It is padded or truncated to the request length.
+    pub fn data(data: &[u8]) -> Self {
+        Self {
+            result: Ok(data.to_vec()),
+            ..Self::ok()
+        }
+    }
+
+    /// Succeed with exactly `data` as the response, even if it is longer than the request.
+    pub fn raw_data(data: &[u8]) -> Self {
+        Self {
+            fit_to_request: false,
+            ..Self::data(data)
+        }
+    }
+
+    /// Fail with `error`.
+    pub fn error(error: Bcme) -> Self {
+        Self {
+            result: Err(error),
+            ..Self::ok()
+        }
+    }
+
+    /// Never respond, as if the firmware was stuck on the request. Events are still sent.
+    pub fn no_response() -> Self {
+        Self {
+            respond: false,
+            ..Self::ok()
+        }
+    }
+
+    /// Send `event` after the response.
+    pub fn event(mut self, event: SimEvent) -> Self {
+        self.events.push(event);
+        self
+    }
+}
+
+/// An event sent by the simulated firmware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimEvent {
+    pub event_type: Event,
+    pub status: u32,
+    pub reason: u32,
+    pub addr: [u8; 6],
+    pub ifidx: u8,
+    pub data: Vec<u8>,
+}
+
+impl SimEvent {
+    pub fn new(event_type: Event, status: u32) -> Self {
+        Self {
+            event_type,
+            status,
+            reason: 0,
+            addr: [0; 6],
+            ifidx: 0,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn reason(self, reason: u32) -> Self {
+        Self { reason, ..self }
+    }
+
+    pub fn addr(self, addr: [u8; 6]) -> Self {
+        Self { addr, ..self }
+    }
+
+    pub fn ifidx(self, ifidx: u8) -> Self {
+        Self { ifidx, ..self }
+    }
+
+    pub fn data(self, data: &[u8]) -> Self {
+        Self {
+            data: data.to_vec(),
+            ..self
+        }
+    }
+
+    /// A partial `ESCAN_RESULT` event reporting one network.
+    pub fn scan_result(bssid: [u8; 6], ssid: &[u8], channel: u8, rssi: i16, ies: &[u8]) -> Self {
+        let ssid_len = ssid.len().min(32);
+        let mut ssid_buf = [0; 32];
+        ssid_buf[..ssid_len].copy_from_slice(&ssid[..ssid_len]);
+
+        let bss = BssInfo {
+            version: 109,
+            length: (BssInfo::SIZE + ies.len()) as u32,
+            bssid,
+            beacon_period: 100,
+            capability: 0,
+            ssid_len: ssid_len as u8,
+            ssid: ssid_buf,
+            _pad1: 0,
+            rateset_count: 0,
+            rates: [0; 16],
+            chanspec: (channel as u32 | CHANSPEC_BAND_2G | CHANSPEC_BW_20) as u16,
+            atim_window: 0,
+            dtim_period: 1,
+            _pad2: 0,
+            rssi,
+            phy_noise: -90,
+            n_cap: 0,
+            _pad3: [0; 2],
+            nbss_cap: 0,
+            ctl_ch: channel,
+            _pad4: [0; 3],
+            _reserved32: 0,
+            flags: 0,
+            _reserved: [0; 3],
+            basic_mcs: [0; 16],
+            ie_offset: BssInfo::SIZE as u16,
+            _pad5: [0; 2],
+            ie_length: ies.len() as u32,
+            snr: rssi + 90,
+            _pad6: [0; 2],
+        };
+
+        let mut data = Vec::new();
+        let buflen = (ScanResults::SIZE + BssInfo::SIZE + ies.len()) as u32;
+        data.extend_from_slice(&buflen.to_le_bytes());
+        data.extend_from_slice(&109u32.to_le_bytes()); // version
+        data.extend_from_slice(&1u16.to_le_bytes()); // sync_id
+        data.extend_from_slice(&1u16.to_le_bytes()); // bss_count
+        data.extend_from_slice(&bss.to_bytes());
+        data.extend_from_slice(ies);
+
+        Self::new(Event::ESCAN_RESULT, EStatus::PARTIAL as u32).data(&data)
+    }
+
+    /// The `ESCAN_RESULT` event that ends a scan.
+    pub fn scan_complete() -> Self {
+        Self::new(Event::ESCAN_RESULT, EStatus::SUCCESS as u32)
+    }
+}
+
+/// The bus of a [`Sim`], implementing [`SpiBusCyw43`].
+pub struct SimBus {
+    sim: Sim,
+}
+
+impl SpiBusCyw43 for SimBus {
+    async fn cmd_write(&mut self, write: &[u32]) -> u32 {
+        self.sim.with(|s| s.cmd_write(write))
+    }
+
+    async fn cmd_read(&mut self, write: u32, read: &mut [u32]) -> u32 {
+        self.sim.with(|s| s.cmd_read(write, read))
+    }
+
+    async fn wait_for_event(&mut self) {
+        poll_fn(|cx| {
+            self.sim.with(|s| {
+                if s.irq_pending() {
+                    Poll::Ready(())
+                } else {
+                    s.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+/// The power pin of a [`Sim`].
+pub struct SimPwr {
+    sim: Sim,
+}
+
+impl embedded_hal_1::digital::ErrorType for SimPwr {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_hal_1::digital::OutputPin for SimPwr {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.sim.with(|s| s.reset());
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+type IoctlHook = Box<dyn FnMut(&IoctlRequest) -> Option<IoctlReply> + Send>;
+
+struct SimState {
+    chip_id: u16,
+    /// Words are 16-bit swapped until the host configures 32-bit mode.
+    swapped: bool,
+    bus_regs: [u8; 0x20],
+    f1_regs: HashMap<u32, u8>,
+    backplane: HashMap<u32, u8>,
+    /// Bring-up stage the chip doesn't get past. Survives resets.
+    stall: Option<InitError>,
+
+    iovars: HashMap<String, Vec<u8>>,
+    ioctls: HashMap<u32, Vec<u8>>,
+    hook: Option<IoctlHook>,
+    log: Vec<IoctlRequest>,
+    /// Clients associated to an AP, as interface, address and RSSI.
+    ap_clients: Vec<(u8, [u8; 6], i32)>,
+
+    /// Frames waiting to be read by the host.
+    rx: VecDeque<Vec<u8>>,
+    tx_packets: Vec<(u8, Vec<u8>)>,
+    seq: u8,
+    host_seq: u8,
+    credit: u8,
+    waker: Option<Waker>,
+}
+
+impl SimState {
+    fn new(chip_id: u16) -> Self {
+        let mut s = Self {
+            chip_id,
+            swapped: true,
+            bus_regs: [0; 0x20],
+            f1_regs: HashMap::new(),
+            backplane: HashMap::new(),
+            stall: None,
+            iovars: HashMap::new(),
+            ioctls: HashMap::new(),
+            hook: None,
+            log: Vec::new(),
+            ap_clients: Vec::new(),
+            rx: VecDeque::new(),
+            tx_packets: Vec::new(),
+            seq: 0,
+            host_seq: 0,
+            credit: 0,
+            waker: None,
+        };
+        s.reset();
+        s.iovars
+            .insert("cur_etheraddr".to_owned(), [0x02, 0, 0, 0, 0, 0x01].to_vec());
+        s
+    }
+
+    fn reset(&mut self) {
+        self.swapped = true;
+        self.bus_regs = [0; 0x20];
+        self.bus_regs[REG_BUS_TEST_RO as usize..][..4].copy_from_slice(&FEEDBEAD.to_le_bytes());
+        self.f1_regs.clear();
+        self.backplane.clear();
+        for (i, b) in (self.chip_id as u32).to_le_bytes().iter().enumerate() {
+            self.backplane.insert(0x1800_0000 + i as u32, *b);
+        }
+        self.rx.clear();
+        self.seq = 0;
+        self.host_seq = 0;
+        self.credit = 0;
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+        if self.stall != Some(InitError::F2Ready) {
+            status |= STATUS_F2_RX_READY;
+        }
+        if let Some(frame) = self.rx.front() {
+            status |= STATUS_F2_PKT_AVAILABLE | (frame.len() as u32) << STATUS_F2_PKT_LEN_SHIFT;
+        }
+        status
+    }
+
+    fn irq_pending(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn cmd_write(&mut self, write: &[u32]) -> u32 {
+        let cmd = self.unswap(write[0]);
+        let (func, addr, len) = decode_cmd(cmd);
+        let mut data = Vec::with_capacity(len);
+        for w in &write[1..] {
+            data.extend_from_slice(&self.unswap(*w).to_le_bytes());
+        }
+        data.truncate(len);
+
+        match func {
+            FUNC_BUS => {
+                for (i, b) in data.iter().enumerate() {
+                    self.bus_write(addr + i as u32, *b);
+                }
+                if addr == REG_BUS_CTRL && data.first().map_or(false, |b| b & WORD_LENGTH_32 as u8 != 0) {
+                    self.swapped = false;
+                }
+            }
+            FUNC_BACKPLANE => {
+                for (i, b) in data.iter().enumerate() {
+                    self.f1_write(addr + i as u32, *b);
+                }
+            }
+            FUNC_WLAN => self.wlan_write(&data),
+            _ => warn!("sim: write to unsupported function {}", func),
+        }
+
+        self.status()
+    }
+
+    fn cmd_read(&mut self, write: u32, read: &mut [u32]) -> u32 {
+        let cmd = self.unswap(write);
+        let (func, addr, len) = decode_cmd(cmd);
+
+        let data: Vec<u8> = match func {
+            FUNC_BUS => (0..len as u32).map(|i| self.bus_read(addr + i)).collect(),
+            FUNC_BACKPLANE => (0..len as u32).map(|i| self.f1_read(addr + i)).collect(),
+            FUNC_WLAN => {
+                let mut frame = self.rx.pop_front().unwrap_or_default();
+                frame.resize(len, 0);
+                frame
+            }
+            _ => {
+                warn!("sim: read from unsupported function {}", func);
+                Vec::new()
+            }
+        };
+
+        // Backplane reads have one word of response delay.
+        let skip = if func == FUNC_BACKPLANE { 1 } else { 0 };
+        read.fill(0);
+        for (word, chunk) in read[skip..].iter_mut().zip(data.chunks(4)) {
+            let mut bytes = [0; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = self.unswap(u32::from_le_bytes(bytes));
+        }
+
+        self.status()
+    }
+
+    fn unswap(&self, word: u32) -> u32 {
+        if self.swapped {
+            word.rotate_left(16)
+        } else {
+            word
+        }
+    }
+
+    fn bus_read(&self, addr: u32) -> u8 {
+        let irq = if self.irq_pending() { IRQ_F2_PACKET_AVAILABLE } else { 0 };
+        match addr {
+            REG_BUS_INTERRUPT => irq as u8,
+            0x05 => (irq >> 8) as u8,
+            0x08..=0x0b => self.status().to_le_bytes()[(addr - 0x08) as usize],
+            _ => self.bus_regs.get(addr as usize).copied().unwrap_or(0),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, val: u8) {
+        match addr {
+            // read only
+            0x04 | 0x05 | 0x08..=0x0b | 0x14..=0x17 => {}
+            _ => {
+                if let Some(reg) = self.bus_regs.get_mut(addr as usize) {
+                    *reg = val;
+                }
+            }
+        }
+    }
+
+    fn window(&self) -> u32 {
+        let reg = |addr| *self.f1_regs.get(&addr).unwrap_or(&0) as u32;
+        (reg(REG_BACKPLANE_BACKPLANE_ADDRESS_HIGH) << 24
+            | reg(REG_BACKPLANE_BACKPLANE_ADDRESS_MID) << 16
+            | reg(REG_BACKPLANE_BACKPLANE_ADDRESS_LOW) << 8)
+            & !BACKPLANE_ADDRESS_MASK
+    }
+
+    fn f1_read(&self, addr: u32) -> u8 {
+        if addr >= 0x10000 {
+            let val = *self.f1_regs.get(&addr).unwrap_or(&0);
+            if addr == REG_BACKPLANE_CHIP_CLOCK_CSR {
+                // ALP and HT clocks are available right away.
+                let mut val = val;
+                if self.stall != Some(InitError::AlpClock) {
+                    val |= BACKPLANE_ALP_AVAIL;
+                }
+                if self.stall != Some(InitError::HtClock) {
+                    val |= 0x80;
+                }
+                return val;
+            }
+            return val;
+        }
+        self.bp_read_byte(self.window() | (addr & BACKPLANE_ADDRESS_MASK))
+    }
+
+    fn f1_write(&mut self, addr: u32, val: u8) {
+        if addr >= 0x10000 {
+            self.f1_regs.insert(addr, val);
+            return;
+        }
+        let addr = self.window() | (addr & BACKPLANE_ADDRESS_MASK);
+        self.backplane.insert(addr, val);
+    }
+
+    fn bp_read_byte(&self, addr: u32) -> u8 {
+        if self.stall == Some(InitError::CoreStart) {
+            // The WLAN core stays in reset.
+            let chip = Chip::from_id(self.chip_id);
+            if chip.map_or(false, |chip| addr == Core::WLAN.base_addr(&chip) + AI_RESETCTRL_OFFSET) {
+                return AI_RESETCTRL_BIT_RESET;
+            }
+        }
+        *self.backplane.get(&addr).unwrap_or(&0)
+    }
+
+    fn wlan_write(&mut self, frame: &[u8]) {
+        let Some(header) = frame.get(..SdpcmHeader::SIZE) else {
+            warn!("sim: short F2 frame");
+            return;
+        };
+        let header = SdpcmHeader::from_bytes(header.try_into().unwrap());
+        let len = (header.len as usize).min(frame.len());
+        let Some(payload) = frame.get(header.header_length as usize..len) else {
+            warn!("sim: bad SDPCM header length");
+            return;
+        };
+        self.host_seq = header.sequence.wrapping_add(1);
+
+        match header.channel_and_flags & 0x0f {
+            CHANNEL_TYPE_CONTROL => self.handle_ioctl(payload),
+            CHANNEL_TYPE_DATA => {
+                if payload.len() < BdcHeader::SIZE {
+                    return;
+                }
+                let iface = payload[2] & BDC_FLAG2_IF_MASK;
+                let offset = BdcHeader::SIZE + payload[3] as usize * 4;
+                if let Some(packet) = payload.get(offset..) {
+                    self.tx_packets.push((iface, packet.to_vec()));
+                }
+                // Make sure the host doesn't run out of credit if we have nothing to send back.
+                if self.credit.wrapping_sub(self.host_seq) < 4 {
+                    self.queue_frame(CHANNEL_TYPE_DATA, &[]);
+                }
+            }
+            channel => warn!("sim: frame on unsupported channel {}", channel),
+        }
+    }
+
+    fn handle_ioctl(&mut self, payload: &[u8]) {
+        let Some(cdc) = payload.get(..CdcHeader::SIZE) else {
+            warn!("sim: short CDC header");
+            return;
+        };
+        let mut cdc = CdcHeader::from_bytes(cdc.try_into().unwrap());
+        let data_len = (cdc.len as usize).min(payload.len() - CdcHeader::SIZE);
+        let data = &payload[CdcHeader::SIZE..][..data_len];
+
+        let req = IoctlRequest {
+            set: cdc.flags & IoctlType::Set as u16 != 0,
+            cmd: cdc.cmd,
+            iface: (cdc.flags >> 12) as u32,
+            data: data.to_vec(),
+        };
+        self.log.push(req.clone());
+
+        let reply = match self.hook.as_mut().and_then(|hook| hook(&req)) {
+            Some(reply) => reply,
+            None => self.default_reply(&req),
+        };
+
+        if reply.respond {
+            let mut response = match reply.result {
+                Ok(d) if d.is_empty() => data.to_vec(),
+                Ok(d) => d,
+                Err(error) => {
+                    cdc.status = i32::from(error) as u32;
+                    data.to_vec()
+                }
+            };
+            if reply.fit_to_request {
+                response.resize(data_len, 0);
+            }
+
+            cdc.len = response.len() as u32;
+            let mut frame = cdc.to_bytes().to_vec();
+            frame.extend_from_slice(&response);
+            self.queue_frame(CHANNEL_TYPE_CONTROL, &frame);
+        }
+
+        for event in reply.events {
+            self.push_event(&event);
+        }
+    }
+
+    fn default_reply(&mut self, req: &IoctlRequest) -> IoctlReply {
+        if !req.set && req.cmd == IOCTL_CMD_GET_ASSOCLIST {
+            return self.assoc_list(req);
+        }
+        // With a `scb_val_t`, the RSSI of an associated client instead of the station's.
+        if !req.set && req.cmd == IOCTL_CMD_GET_RSSI && req.data.len() >= ScbVal::SIZE {
+            let addr = &req.data[4..10];
+            let client = self
+                .ap_clients
+                .iter()
+                .find(|(i, a, _)| *i as u32 == req.iface && a == addr);
+            return match client {
+                Some((_, _, rssi)) => IoctlReply::data(&rssi.to_le_bytes()),
+                None => IoctlReply::error(Bcme::BADADDR),
+            };
+        }
+
+        match (req.iovar_name(), req.set) {
+            (Some(name), true) => {
+                let value = req.iovar_value().unwrap_or_default().to_vec();
+                self.iovars.insert(name.to_owned(), value);
+                IoctlReply::ok()
+            }
+            (Some(name), false) => match self.iovars.get(name) {
+                Some(value) => IoctlReply::data(value),
+                None => IoctlReply::data(&[0]),
+            },
+            (None, true) => {
+                self.ioctls.insert(req.cmd, req.data.clone());
+                IoctlReply::ok()
+            }
+            (None, false) => match self.ioctls.get(&req.cmd) {
+                Some(value) => IoctlReply::data(value),
+                None => IoctlReply::data(&[0]),
+            },
+        }
+    }
+
+    /// `struct maclist { uint count; struct ether_addr ea[]; }`, with the request's `count` as
+    /// the capacity.
+    fn assoc_list(&self, req: &IoctlRequest) -> IoctlReply {
+        let Some(count) = req.data.get(0..4) else {
+            return IoctlReply::error(Bcme::BUFTOOSHORT);
+        };
+        let capacity = (u32::from_le_bytes(count.try_into().unwrap()) as usize).min((req.data.len() - 4) / 6);
+        let clients: Vec<_> = self
+            .ap_clients
+            .iter()
+            .filter(|(i, _, _)| *i as u32 == req.iface)
+            .map(|(_, addr, _)| addr)
+            .collect();
+        if clients.len() > capacity {
+            return IoctlReply::error(Bcme::BUFTOOSHORT);
+        }
+
+        let mut res = (clients.len() as u32).to_le_bytes().to_vec();
+        for addr in clients {
+            res.extend_from_slice(addr);
+        }
+        IoctlReply::data(&res)
+    }
+
+    fn push_event(&mut self, event: &SimEvent) {
+        let mut packet = EventPacket {
+            eth: EthernetHeader {
+                destination_mac: [0xff; 6],
+                source_mac: [0; 6],
+                ether_type: 0x886c,
+            },
+            hdr: EventHeader {
+                subtype: 0x8001,
+                length: (EventHeader::SIZE + EventMessage::SIZE + event.data.len()) as u16,
+                version: 0,
+                oui: [0x00, 0x10, 0x18],
+                user_subtype: 1,
+            },
+            msg: EventMessage {
+                version: 2,
+                flags: 0,
+                event_type: event.event_type as u8 as u32,
+                status: event.status,
+                reason: event.reason,
+                auth_type: 0,
+                datalen: event.data.len() as u32,
+                addr: event.addr,
+                ifname: [0; 16],
+                ifidx: event.ifidx,
+                bsscfgidx: event.ifidx,
+            },
+        };
+        packet.byteswap();
+
+        let bdc = BdcHeader {
+            flags: BDC_VERSION << BDC_VERSION_SHIFT,
+            priority: 0,
+            flags2: event.ifidx,
+            data_offset: 0,
+        };
+        let mut frame = bdc.to_bytes().to_vec();
+        frame.extend_from_slice(&packet.to_bytes());
+        frame.extend_from_slice(&event.data);
+        self.queue_frame(CHANNEL_TYPE_EVENT, &frame);
+    }
+
+    fn push_packet(&mut self, iface: u8, packet: &[u8]) {
+        let bdc = BdcHeader {
+            flags: BDC_VERSION << BDC_VERSION_SHIFT,
+            priority: 0,
+            flags2: iface,
+            data_offset: 0,
+        };
+        let mut frame = bdc.to_bytes().to_vec();
+        frame.extend_from_slice(packet);
+        self.queue_frame(CHANNEL_TYPE_DATA, &frame);
+    }
+
+    fn queue_frame(&mut self, channel: u8, payload: &[u8]) {
+        let total_len = SdpcmHeader::SIZE + payload.len();
+        if total_len > MAX_FRAME_LEN {
+            warn!("sim: dropping {} byte frame, too long", total_len);
+            return;
+        }
+
+        self.credit = self.host_seq.wrapping_add(0x3f);
+        let header = SdpcmHeader {
+            len: total_len as u16,
+            len_inv: !(total_len as u16),
+            sequence: self.seq,
+            channel_and_flags: channel,
+            next_length: 0,
+            header_length: SdpcmHeader::SIZE as u8,
+            wireless_flow_control: 0,
+            bus_data_credit: self.credit,
+            reserved: [0; 2],
+        };
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        self.rx.push_back(frame);
+        self.wake();
+    }
+}
+
+/// Run a future to completion on the current thread.
+///
+/// The driver only needs a timer queue to run, so tests don't need an executor: poll the runner
+/// and the test body together, e.g. with `embassy_futures::select`.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = core::pin::pin!(fut);
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+            return res;
+        }
+        thread::park();
+    }
+}
+
+pub(crate) struct ThreadWaker(pub(crate) Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn decode_cmd(cmd: u32) -> (u32, u32, usize) {
+    let func = (cmd >> 28) & 0b11;
+    let addr = (cmd >> 11) & 0x1ffff;
+    let len = match cmd & 0x7ff {
+        0 => 2048,
+        len => len as usize,
+    };
+    (func, addr, len)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use core::future::poll_fn;
+    use std::vec::Vec;
+
+    use ch::driver::{Driver, LinkState, RxToken, TxToken};
+    use embassy_futures::select::{select, Either};
+    use embassy_net_driver_channel as ch;
+    use embassy_time::{with_timeout, Duration, Timer};
+
+    use super::*;
+    use crate::control::{ApClientEvent, Error, JoinOptions};
+    use crate::{countries, ApState, Control, NetDriver, Runner, State, MAX_IOCTL_LEN};
+
+    /// Run `test` while the runner runs. Fails instead of hanging if the driver gets stuck.
+    async fn run<F: Future>(runner: Runner<'_, SimPwr, SimBus>, test: F) -> F::Output {
+        match select(runner.run(), with_timeout(Duration::from_secs(10), test)).await {
+            Either::First(never) => never,
+            Either::Second(res) => res.expect("test timed out"),
+        }
+    }
+
+    /// Bring up a driver on `sim` and initialize it, then run `test` while the runner runs.
+    ///
+    /// `test` gets the network device, `Control` and `sim` back.
+    pub(crate) fn with_sim<F, Fut>(sim: Sim, test: F) -> Fut::Output
+    where
+        F: FnOnce(NetDriver<'static>, Control<'static>, Sim) -> Fut,
+        Fut: Future,
+    {
+        // Leaked, so that `test` can take the handles without borrowing from here.
+        let state = Box::leak(Box::new(State::new()));
+        block_on(async {
+            let (net, mut control, runner) = crate::new(state, sim.pwr(), sim.bus(), &[0; 4]).await.unwrap();
+            run(runner, async {
+                control.init(&[0; 4], countries::WORLD_WIDE_XX).await.unwrap();
+                test(net, control, sim).await
+            })
+            .await
+        })
+    }
+
+    pub(crate) async fn link_up(dev: &mut NetDriver<'_>) -> bool {
+        poll_fn(|cx| Poll::Ready(dev.link_state(cx) == LinkState::Up)).await
+    }
+
+    pub(crate) async fn recv(dev: &mut NetDriver<'_>) -> Vec<u8> {
+        poll_fn(|cx| match dev.receive(cx) {
+            Some((rx, _)) => Poll::Ready(rx.consume(|buf| buf.to_vec())),
+            None => Poll::Pending,
+        })
+        .await
+    }
+
+    pub(crate) async fn send(dev: &mut NetDriver<'_>, data: &[u8]) {
+        poll_fn(|cx| match dev.transmit(cx) {
+            Some(tx) => Poll::Ready(tx.consume(data.len(), |buf| buf.copy_from_slice(data))),
+            None => Poll::Pending,
+        })
+        .await
+    }
+
+    /// The set ioctls in `log`, as the command and the iovar name.
+    fn sets(log: &[IoctlRequest]) -> Vec<(u32, Option<&str>)> {
+        log.iter().filter(|r| r.set).map(|r| (r.cmd, r.iovar_name())).collect()
+    }
+
+    pub(crate) fn escan_action(req: &IoctlRequest) -> Option<u16> {
+        let value = req.iovar_value()?;
+        (req.iovar_name()? == "escan").then(|| u16::from_le_bytes([value[4], value[5]]))
+    }
+
+    #[test]
+    fn clm_load_failure() {
+        let sim = Sim::new();
+        sim.set_iovar("clmload_status", &7u32.to_le_bytes());
+        let mut state = State::new();
+        block_on(async {
+            let (_net, mut control, runner) = crate::new(&mut state, sim.pwr(), sim.bus(), &[0; 4]).await.unwrap();
+            run(runner, async {
+                let err = control.init(&[0; 4], countries::WORLD_WIDE_XX).await;
+                assert_eq!(err, Err(Error::ClmLoadFailed { status: 7 }));
+            })
+            .await
+        });
+    }
+
+    #[test]
+    fn join() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match (req.iovar_name(), req.cmd) {
+            (Some("join"), _) => Some(
+                IoctlReply::ok()
+                    .event(SimEvent::new(Event::AUTH, EStatus::SUCCESS as u32))
+                    .event(SimEvent::new(Event::SET_SSID, EStatus::SUCCESS as u32)),
+            ),
+            (None, IOCTL_CMD_DISASSOC) => Some(IoctlReply::ok().event(SimEvent::new(Event::DISASSOC, 0))),
+            _ => None,
+        });
+
+        with_sim(sim, |mut net, mut control, sim| async move {
+            assert!(!link_up(&mut net).await);
+
+            control.join("network", JoinOptions::new("password")).await.unwrap();
+            assert!(link_up(&mut net).await);
+            assert_eq!(control.disconnect_reason(), None);
+
+            let pmk = sim.ioctl(IOCTL_CMD_SET_PASSPHRASE).unwrap();
+            assert_eq!(&pmk[4..12], b"password");
+
+            control.leave().await.unwrap();
+            assert!(!link_up(&mut net).await);
+        });
+    }
+
+    #[test]
+    fn join_falls_back_to_set_ssid() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match (req.iovar_name(), req.cmd) {
+            (Some("join"), _) => Some(IoctlReply::error(Bcme::UNSUPPORTED)),
+            (None, IOCTL_CMD_SET_SSID) => Some(IoctlReply::ok().event(SimEvent::new(Event::SET_SSID, 0))),
+            _ => None,
+        });
+
+        with_sim(sim, |mut net, mut control, _sim| async move {
+            control.join_open("network").await.unwrap();
+            assert!(link_up(&mut net).await);
+        });
+    }
+
+    #[test]
+    fn join_failure() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match req.iovar_name() {
+            // Fails, but the join timeout is still reported.
+            None if req.cmd == IOCTL_CMD_DISASSOC => Some(IoctlReply::error(Bcme::NOTASSOCIATED)),
+            // The SSID follows its length in the join params.
+            Some("join") if req.iovar_value()?[4] == b'a' => Some(
+                IoctlReply::ok()
+                    .event(SimEvent::new(Event::AUTH, EStatus::FAIL as u32))
+                    .event(SimEvent::new(Event::SET_SSID, EStatus::NO_NETWORKS as u32)),
+            ),
+            Some("join") if req.iovar_value()?[4] == b'b' => Some(IoctlReply::error(Bcme::BADARG)),
+            // Never completes.
+            Some("join") => Some(IoctlReply::ok()),
+            _ => None,
+        });
+
+        with_sim(sim, |mut net, mut control, sim| async move {
+            let err = control.join_open("a").await;
+            assert_eq!(
+                err,
+                Err(Error::JoinFailed {
+                    status: EStatus::NO_NETWORKS as u32
+                })
+            );
+
+            let err = control.join_open("b").await;
+            assert_eq!(err, Err(Error::Ioctl(Bcme::BADARG)));
+
+            let err = control.join("network", JoinOptions::new("short")).await;
+            assert_eq!(err, Err(Error::InvalidPassphrase));
+
+            let sent = sim.ioctl_log().len();
+            let err = control.join_open(&"x".repeat(33)).await;
+            assert_eq!(err, Err(Error::InvalidSsid));
+            assert_eq!(sim.ioctl_log().len(), sent);
+
+            let options = JoinOptions::new_open().timeout(Duration::from_millis(50));
+            assert_eq!(control.join("c", options).await, Err(Error::Timeout));
+            let log = sim.ioctl_log();
+            assert_eq!(log.last().map(|req| req.cmd), Some(IOCTL_CMD_DISASSOC));
+
+            assert!(!link_up(&mut net).await);
+        });
+    }
+
+    #[test]
+    fn scan() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match escan_action(req)? {
+            ESCAN_ACTION_START => Some(
+                IoctlReply::ok()
+                    .event(SimEvent::scan_result([1; 6], b"one", 1, -40, &[0, 3, b'o', b'n', b'e']))
+                    .event(SimEvent::scan_result([2; 6], b"two", 6, -60, &[]))
+                    .event(SimEvent::scan_complete()),
+            ),
+            _ => None,
+        });
+
+        with_sim(sim, |_net, mut control, sim| async move {
+            for _ in 0..2 {
+                let mut scanner = control.scan().await.unwrap();
+                let mut results = Vec::new();
+                while let Some(result) = scanner.next().await {
+                    results.push((result.bssid, result.ssid().to_vec(), result.channel(), result.rssi()));
+                }
+                assert_eq!(
+                    results,
+                    [([1; 6], b"one".to_vec(), 1, -40), ([2; 6], b"two".to_vec(), 6, -60)]
+                );
+                assert!(scanner.next().await.is_none());
+            }
+
+            // Completed scans aren't aborted.
+            Timer::after(Duration::from_millis(10)).await;
+            let log = sim.ioctl_log();
+            assert!(!log.iter().any(|req| escan_action(req) == Some(ESCAN_ACTION_ABORT)));
+        });
+    }
+
+    #[test]
+    fn scan_aborted_on_drop() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match escan_action(req)? {
+            ESCAN_ACTION_START => Some(IoctlReply::ok().event(SimEvent::scan_result([1; 6], b"one", 1, -40, &[]))),
+            _ => None,
+        });
+
+        with_sim(sim, |_net, mut control, sim| async move {
+            let mut scanner = control.scan().await.unwrap();
+            assert!(scanner.next().await.is_some());
+            drop(scanner);
+
+            Timer::after(Duration::from_millis(10)).await;
+            let log = sim.ioctl_log();
+            assert!(log.iter().any(|req| escan_action(req) == Some(ESCAN_ACTION_ABORT)));
+        });
+    }
+
+    #[test]
+    fn get_iovar_lengths() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match req.iovar_name() {
+            Some("long") => Some(IoctlReply::raw_data(&[0xab; 64])),
+            Some("full") => Some(IoctlReply::data(&[0xcd; MAX_IOCTL_LEN])),
+            _ => None,
+        });
+
+        with_sim(sim, |_net, mut control, sim| async move {
+            // Responses longer than the buffer are truncated.
+            let mut buf = [0; 16];
+            assert_eq!(control.get_iovar("long", &mut buf).await, Ok(16));
+            assert_eq!(buf, [0xab; 16]);
+
+            let mut buf = [0; 2];
+            assert_eq!(control.get_iovar("long", &mut buf).await, Ok(2));
+            assert_eq!(buf, [0xab; 2]);
+
+            let mut buf = [0; MAX_IOCTL_LEN];
+            assert_eq!(control.get_iovar("full", &mut buf).await, Ok(MAX_IOCTL_LEN));
+            assert!(buf.iter().all(|&b| b == 0xcd));
+
+            let sent = sim.ioctl_log().len();
+            let mut buf = [0; MAX_IOCTL_LEN + 1];
+            let err = control.get_iovar("full", &mut buf).await;
+            assert_eq!(err, Err(Error::BufferTooLong { max: MAX_IOCTL_LEN }));
+            assert_eq!(sim.ioctl_log().len(), sent);
+        });
+    }
+
+    #[test]
+    fn start_stop_ap() {
+        let sim = Sim::new();
+        sim.on_ioctl(|req| match req.iovar_name() {
+            Some("join") => Some(IoctlReply::ok().event(SimEvent::new(Event::SET_SSID, EStatus::SUCCESS as u32))),
+            _ => None,
+        });
+        with_sim(sim, |mut net, mut control, sim| async move {
+            let err = control.start_ap_wpa2("ap", "short", 6).await;
+            assert_eq!(err, Err(Error::InvalidPassphrase));
+            let err = control.start_ap_open(&"x".repeat(33), 6).await;
+            assert_eq!(err, Err(Error::InvalidSsid));
+            assert_eq!(sim.ioctl(IOCTL_CMD_SET_AP), None);
+
+            control.join_open("network").await.unwrap();
+            assert!(link_up(&mut net).await);
+
+            let start = sim.ioctl_log().len();
+            control.start_ap_wpa2("ap", "password", 6).await.unwrap();
+            // The station connection is gone, without a disconnect reason.
+            assert!(!link_up(&mut net).await);
+            assert_eq!(control.disconnect_reason(), None);
+            assert_eq!(
+                sets(&sim.ioctl_log()[start..]),
+                [
+                    (IOCTL_CMD_DOWN, None),
+                    (IOCTL_CMD_SET_VAR, Some("apsta")),
+                    (IOCTL_CMD_UP, None),
+                    (IOCTL_CMD_SET_AP, None),
+                    (IOCTL_CMD_SET_VAR, Some("bsscfg:ssid")),
+                    (IOCTL_CMD_SET_CHANNEL, None),
+                    (IOCTL_CMD_SET_VAR, Some("bsscfg:wsec")),
+                    (IOCTL_CMD_SET_VAR, Some("bsscfg:wpa_auth")),
+                    (IOCTL_CMD_SET_PASSPHRASE, None),
+                    (IOCTL_CMD_SET_VAR, Some("2g_mrate")),
+                    (IOCTL_CMD_SET_VAR, Some("bss")),
+                ]
+            );
+            assert_eq!(sim.ioctl(IOCTL_CMD_SET_AP), Some(Vec::from([1, 0, 0, 0])));
+            assert_eq!(sim.ioctl(IOCTL_CMD_SET_CHANNEL), Some(Vec::from([6, 0, 0, 0])));
+            assert_eq!(sim.iovar("apsta"), Some(Vec::from([0, 0, 0, 0])));
+            assert_eq!(sim.iovar("bss"), Some(Vec::from([0, 0, 0, 0, 1, 0, 0, 0])));
+            assert_eq!(&sim.iovar("bsscfg:ssid").unwrap()[4..10], [2, 0, 0, 0, b'a', b'p']);
+
+            control.stop_ap().await.unwrap();
+            assert_eq!(sim.ioctl(IOCTL_CMD_SET_AP), Some(Vec::from([0, 0, 0, 0])));
+            assert_eq!(sim.iovar("apsta"), Some(Vec::from([1, 0, 0, 0])));
+            assert_eq!(sim.iovar("bss"), Some(Vec::from([0; 8])));
+        });
+    }
+
+    #[test]
+    fn ap_clients() {
+        const A: [u8; 6] = [0x02, 0, 0, 0, 0, 0xaa];
+        const B: [u8; 6] = [0x02, 0, 0, 0, 0, 0xbb];
+
+        with_sim(Sim::new(), |_net, mut control, sim| async move {
+            control.start_ap_open("ap", 6).await.unwrap();
+            let mut events = control.ap_client_events().unwrap();
+
+            // Clients of other interfaces are not reported.
+            sim.associate(1, B, -40);
+            sim.associate(0, A, -50);
+            assert_eq!(events.next().await, ApClientEvent::Assoc { addr: A });
+            sim.associate(0, B, -60);
+            assert_eq!(events.next().await, ApClientEvent::Assoc { addr: B });
+
+            let mut clients = [[0; 6]; 4];
+            assert_eq!(control.ap_clients(&mut clients).await, Ok(2));
+            assert_eq!(clients[..2], [A, B]);
+            let mut clients = [[0; 6]; 1];
+            assert_eq!(control.ap_clients(&mut clients).await, Ok(1));
+            assert_eq!(clients, [A]);
+
+            assert_eq!(control.ap_client_rssi(A).await, Ok(-50));
+            assert_eq!(control.ap_client_rssi(B).await, Ok(-60));
+            let err = control.ap_client_rssi([0x02, 0, 0, 0, 0, 0xcc]).await;
+            assert_eq!(err, Err(Error::Ioctl(Bcme::BADADDR)));
+
+            sim.disassociate(0, A, 8);
+            let event = events.next().await;
+            assert_eq!(event, ApClientEvent::Disassoc { addr: A, reason: 8 });
+            assert_eq!(event.addr(), A);
+            let mut clients = [[0; 6]; 4];
+            assert_eq!(control.ap_clients(&mut clients).await, Ok(1));
+            assert_eq!(clients[0], B);
+        });
+    }
+
+    #[test]
+    fn apsta_routing() {
+        let sim = Sim::new();
+        sim.set_mac_addr([0, 1, 2, 3, 4, 5]);
+        let mut state = State::new();
+        let mut ap_state = ApState::new();
+        block_on(async {
+            let (mut sta, mut ap, mut control, runner) =
+                crate::new_apsta(&mut state, &mut ap_state, sim.pwr(), sim.bus(), &[0; 4])
+                    .await
+                    .unwrap();
+            run(runner, async {
+                control.init(&[0; 4], countries::WORLD_WIDE_XX).await.unwrap();
+
+                let start = sim.ioctl_log().len();
+                control.start_ap_open("ap", 11).await.unwrap();
+                assert!(link_up(&mut ap).await);
+                // The SSID creates bsscfg 1, which is then configured and brought up.
+                let log = sim.ioctl_log();
+                assert_eq!(
+                    sets(&log[start..]),
+                    [
+                        (IOCTL_CMD_SET_VAR, Some("bsscfg:ssid")),
+                        (IOCTL_CMD_SET_VAR, Some("bsscfg:cur_etheraddr")),
+                        (IOCTL_CMD_SET_CHANNEL, None),
+                        (IOCTL_CMD_SET_VAR, Some("bsscfg:wsec")),
+                        (IOCTL_CMD_SET_VAR, Some("2g_mrate")),
+                        (IOCTL_CMD_SET_VAR, Some("bss")),
+                    ]
+                );
+                assert!(!link_up(&mut sta).await);
+                assert_eq!(sim.iovar("bss"), Some(Vec::from([1, 0, 0, 0, 1, 0, 0, 0])));
+                let ap_addr = sim.iovar("bsscfg:cur_etheraddr").unwrap();
+                assert_eq!(ap_addr, [1, 0, 0, 0, 2, 1, 2, 3, 4, 5]);
+
+                sim.push_packet(1, &[0xbb; 60]);
+                sim.push_packet(0, &[0xaa; 60]);
+                assert_eq!(recv(&mut sta).await, [0xaa; 60]);
+                assert_eq!(recv(&mut ap).await, [0xbb; 60]);
+
+                send(&mut ap, &[0xcc; 60]).await;
+                send(&mut sta, &[0xdd; 60]).await;
+                Timer::after(Duration::from_millis(10)).await;
+                let mut tx = sim.take_tx_packets();
+                tx.sort();
+                assert_eq!(tx, [(0, Vec::from([0xdd; 60])), (1, Vec::from([0xcc; 60]))]);
+
+                // Packets for an interface that doesn't exist are dropped.
+                sim.push_packet(2, &[0xee; 60]);
+                sim.push_packet(0, &[0xff; 60]);
+                assert_eq!(recv(&mut sta).await, [0xff; 60]);
+
+                control.stop_ap().await.unwrap();
+                assert!(!link_up(&mut ap).await);
+                assert_eq!(sim.iovar("bss"), Some(Vec::from([1, 0, 0, 0, 0, 0, 0, 0])));
+            })
+            .await
+        });
+    }
+}
@@ -1,129 +1,219 @@
+#![forbid(unsafe_code)]
+
 use crate::events::Event;
 use crate::fmt::Bytes;
 
-macro_rules! impl_bytes {
-    ($t:ident) => {
-        impl $t {
-            pub const SIZE: usize = core::mem::size_of::<Self>();
+/// Fixed-size wire encoding of a structure exchanged with the chip, little-endian.
+///
+/// Unlike transmuting, decoding can't fail or read out of bounds for any input: callers check
+/// the length, and every bit pattern is a valid value.
+pub trait Wire: Sized {
+    const SIZE: usize;
 
-            #[allow(unused)]
-            pub fn to_bytes(&self) -> [u8; Self::SIZE] {
-                unsafe { core::mem::transmute(*self) }
+    /// Decode from `buf`, which must be exactly `SIZE` bytes long.
+    fn read(buf: &[u8]) -> Self;
+
+    /// Encode into `buf`, which must be exactly `SIZE` bytes long.
+    fn write(&self, buf: &mut [u8]);
+}
+
+macro_rules! impl_wire_int {
+    ($($t:ty),*) => {
+        $(
+            impl Wire for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+
+                fn read(buf: &[u8]) -> Self {
+                    Self::from_le_bytes(buf.try_into().unwrap())
+                }
+
+                fn write(&self, buf: &mut [u8]) {
+                    buf.copy_from_slice(&self.to_le_bytes());
+                }
             }
+        )*
+    };
+}
+impl_wire_int!(u8, i8, u16, i16, u32, i32);
+
+impl<T: Wire, const N: usize> Wire for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn read(buf: &[u8]) -> Self {
+        core::array::from_fn(|i| T::read(&buf[i * T::SIZE..][..T::SIZE]))
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        for (item, buf) in self.iter().zip(buf.chunks_exact_mut(T::SIZE)) {
+            item.write(buf);
+        }
+    }
+}
+
+/// Define a wire structure, encoded field by field in declaration order.
+macro_rules! wire_struct {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $vis:vis $field:ident: $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        pub struct $name {
+            $(
+                $(#[$field_attr])*
+                $vis $field: $ty,
+            )*
+        }
+
+        impl Wire for $name {
+            const SIZE: usize = 0 $(+ <$ty as Wire>::SIZE)*;
+
+            fn read(buf: &[u8]) -> Self {
+                let mut pos = 0;
+                $(
+                    let $field = <$ty as Wire>::read(&buf[pos..][..<$ty as Wire>::SIZE]);
+                    pos += <$ty as Wire>::SIZE;
+                )*
+                let _ = pos;
+                Self { $($field),* }
+            }
+
+            fn write(&self, buf: &mut [u8]) {
+                let mut pos = 0;
+                $(
+                    Wire::write(&{ self.$field }, &mut buf[pos..][..<$ty as Wire>::SIZE]);
+                    pos += <$ty as Wire>::SIZE;
+                )*
+                let _ = pos;
+            }
+        }
+
+        // The wire layout must match the C layout the struct describes.
+        const _: () = core::assert!(<$name as Wire>::SIZE == core::mem::size_of::<$name>());
+
+        impl $name {
+            pub const SIZE: usize = <Self as Wire>::SIZE;
 
             #[allow(unused)]
-            pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> &Self {
-                let alignment = core::mem::align_of::<Self>();
-                assert_eq!(
-                    bytes.as_ptr().align_offset(alignment),
-                    0,
-                    "{} is not aligned",
-                    core::any::type_name::<Self>()
-                );
-                unsafe { core::mem::transmute(bytes) }
+            pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+                let mut buf = [0; Self::SIZE];
+                Wire::write(self, &mut buf);
+                buf
             }
 
             #[allow(unused)]
-            pub fn from_bytes_mut(bytes: &mut [u8; Self::SIZE]) -> &mut Self {
-                let alignment = core::mem::align_of::<Self>();
-                assert_eq!(
-                    bytes.as_ptr().align_offset(alignment),
-                    0,
-                    "{} is not aligned",
-                    core::any::type_name::<Self>()
-                );
-
-                unsafe { core::mem::transmute(bytes) }
+            pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+                Wire::read(bytes)
             }
         }
     };
 }
 
-#[derive(Clone, Copy)]
+/// Error parsing a frame received from the chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct SharedMemData {
-    pub flags: u32,
-    pub trap_addr: u32,
-    pub assert_exp_addr: u32,
-    pub assert_file_addr: u32,
-    pub assert_line: u32,
-    pub console_addr: u32,
-    pub msgtrace_addr: u32,
-    pub fwid: u32,
-}
-impl_bytes!(SharedMemData);
-
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct SharedMemLog {
-    pub buf: u32,
-    pub buf_size: u32,
-    pub idx: u32,
-    pub out_idx: u32,
+pub enum ParseError {
+    /// The buffer is shorter than the header.
+    TooShort,
+    /// The SDPCM length doesn't match its complement or the length of the bus transfer.
+    LengthMismatch,
+    /// The SDPCM header length is smaller than the header itself.
+    InvalidHeaderLength,
+    /// A length or offset in the header points outside the buffer.
+    Truncated,
 }
-impl_bytes!(SharedMemLog);
 
-#[derive(Debug, Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct SdpcmHeader {
-    pub len: u16,
-    pub len_inv: u16,
-    /// Rx/Tx sequence number
-    pub sequence: u8,
-    ///  4 MSB Channel number, 4 LSB arbitrary flag
-    pub channel_and_flags: u8,
-    /// Length of next data frame, reserved for Tx
-    pub next_length: u8,
-    /// Data offset
-    pub header_length: u8,
-    /// Flow control bits, reserved for Tx
-    pub wireless_flow_control: u8,
-    /// Maximum Sequence number allowed by firmware for Tx
-    pub bus_data_credit: u8,
-    /// Reserved
-    pub reserved: [u8; 2],
-}
-impl_bytes!(SdpcmHeader);
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SharedMemData {
+        pub flags: u32,
+        pub trap_addr: u32,
+        pub assert_exp_addr: u32,
+        pub assert_file_addr: u32,
+        pub assert_line: u32,
+        pub console_addr: u32,
+        pub msgtrace_addr: u32,
+        pub fwid: u32,
+    }
+}
+
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SharedMemLog {
+        pub buf: u32,
+        pub buf_size: u32,
+        pub idx: u32,
+        pub out_idx: u32,
+    }
+}
+
+wire_struct! {
+    #[derive(Debug, Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SdpcmHeader {
+        pub len: u16,
+        pub len_inv: u16,
+        /// Rx/Tx sequence number
+        pub sequence: u8,
+        ///  4 MSB Channel number, 4 LSB arbitrary flag
+        pub channel_and_flags: u8,
+        /// Length of next data frame, reserved for Tx
+        pub next_length: u8,
+        /// Data offset
+        pub header_length: u8,
+        /// Flow control bits, reserved for Tx
+        pub wireless_flow_control: u8,
+        /// Maximum Sequence number allowed by firmware for Tx
+        pub bus_data_credit: u8,
+        /// Reserved
+        pub reserved: [u8; 2],
+    }
+}
 
 impl SdpcmHeader {
-    pub fn parse(packet: &mut [u8]) -> Option<(&mut Self, &mut [u8])> {
-        let packet_len = packet.len();
-        if packet_len < Self::SIZE {
-            warn!("packet too short, len={}", packet.len());
-            return None;
-        }
-        let (sdpcm_header, sdpcm_packet) = packet.split_at_mut(Self::SIZE);
-        let sdpcm_header = Self::from_bytes_mut(sdpcm_header.try_into().unwrap());
-        trace!("rx {:?}", sdpcm_header);
+    pub fn parse(packet: &mut [u8]) -> Result<(Self, &mut [u8]), ParseError> {
+        let header = Self::from_bytes(
+            packet
+                .get(..Self::SIZE)
+                .ok_or(ParseError::TooShort)?
+                .try_into()
+                .unwrap(),
+        );
+        trace!("rx {:?}", header);
 
-        if sdpcm_header.len != !sdpcm_header.len_inv {
-            warn!("len inv mismatch");
-            return None;
+        if header.len != !header.len_inv || header.len as usize != packet.len() {
+            return Err(ParseError::LengthMismatch);
         }
 
-        if sdpcm_header.len as usize != packet_len {
-            warn!("len from header doesn't match len from spi");
-            return None;
+        let start = header.header_length as usize;
+        if start < Self::SIZE {
+            return Err(ParseError::InvalidHeaderLength);
         }
-
-        let sdpcm_packet = &mut sdpcm_packet[(sdpcm_header.header_length as usize - Self::SIZE)..];
-        Some((sdpcm_header, sdpcm_packet))
+        let payload = packet.get_mut(start..).ok_or(ParseError::Truncated)?;
+        Ok((header, payload))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C, packed(2))]
-pub struct CdcHeader {
-    pub cmd: u32,
-    pub len: u32,
-    pub flags: u16,
-    pub id: u16,
-    pub status: u32,
+wire_struct! {
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C, packed(2))]
+    pub struct CdcHeader {
+        pub cmd: u32,
+        pub len: u32,
+        pub flags: u16,
+        pub id: u16,
+        pub status: u32,
+    }
 }
-impl_bytes!(CdcHeader);
 
 #[cfg(feature = "defmt")]
 impl defmt::Format for CdcHeader {
@@ -145,62 +235,68 @@ impl defmt::Format for CdcHeader {
 }
 
 impl CdcHeader {
-    pub fn parse(packet: &mut [u8]) -> Option<(&mut Self, &mut [u8])> {
-        if packet.len() < Self::SIZE {
-            warn!("payload too short, len={}", packet.len());
-            return None;
-        }
-
-        let (cdc_header, payload) = packet.split_at_mut(Self::SIZE);
-        let cdc_header = Self::from_bytes_mut(cdc_header.try_into().unwrap());
+    pub fn parse(packet: &mut [u8]) -> Result<(Self, &mut [u8]), ParseError> {
+        let header = Self::from_bytes(
+            packet
+                .get(..Self::SIZE)
+                .ok_or(ParseError::TooShort)?
+                .try_into()
+                .unwrap(),
+        );
 
-        let payload = &mut payload[..cdc_header.len as usize];
-        Some((cdc_header, payload))
+        let len = header.len as usize;
+        let payload = packet[Self::SIZE..].get_mut(..len).ok_or(ParseError::Truncated)?;
+        Ok((header, payload))
     }
 }
 
 pub const BDC_VERSION: u8 = 2;
 pub const BDC_VERSION_SHIFT: u8 = 4;
-
-#[derive(Debug, Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct BdcHeader {
-    pub flags: u8,
-    /// 802.1d Priority (low 3 bits)
-    pub priority: u8,
-    pub flags2: u8,
-    /// Offset from end of BDC header to packet data, in 4-uint8_t words. Leaves room for optional headers.
-    pub data_offset: u8,
+/// Interface index, in `flags2`.
+pub const BDC_FLAG2_IF_MASK: u8 = 0x0f;
+
+wire_struct! {
+    #[derive(Debug, Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct BdcHeader {
+        pub flags: u8,
+        /// 802.1d Priority (low 3 bits)
+        pub priority: u8,
+        pub flags2: u8,
+        /// Offset from end of BDC header to packet data, in 4-uint8_t words. Leaves room for optional headers.
+        pub data_offset: u8,
+    }
 }
-impl_bytes!(BdcHeader);
 
 impl BdcHeader {
-    pub fn parse(packet: &mut [u8]) -> Option<(&mut Self, &mut [u8])> {
-        if packet.len() < Self::SIZE {
-            return None;
-        }
-
-        let (bdc_header, bdc_packet) = packet.split_at_mut(Self::SIZE);
-        let bdc_header = Self::from_bytes_mut(bdc_header.try_into().unwrap());
-        trace!("    {:?}", bdc_header);
-
-        let packet_start = 4 * bdc_header.data_offset as usize;
+    pub fn parse(packet: &mut [u8]) -> Result<(Self, &mut [u8]), ParseError> {
+        let header = Self::from_bytes(
+            packet
+                .get(..Self::SIZE)
+                .ok_or(ParseError::TooShort)?
+                .try_into()
+                .unwrap(),
+        );
+        trace!("    {:?}", header);
 
-        let bdc_packet = bdc_packet.get_mut(packet_start..)?;
-        trace!("    {:02x}", Bytes(&bdc_packet[..bdc_packet.len().min(36)]));
+        let packet_start = Self::SIZE + 4 * header.data_offset as usize;
+        let payload = packet.get_mut(packet_start..).ok_or(ParseError::Truncated)?;
+        trace!("    {:02x}", Bytes(&payload[..payload.len().min(36)]));
 
-        Some((bdc_header, bdc_packet))
+        Ok((header, payload))
     }
 }
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct EthernetHeader {
-    pub destination_mac: [u8; 6],
-    pub source_mac: [u8; 6],
-    pub ether_type: u16,
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct EthernetHeader {
+        pub destination_mac: [u8; 6],
+        pub source_mac: [u8; 6],
+        pub ether_type: u16,
+    }
 }
 
 impl EthernetHeader {
@@ -209,15 +305,17 @@ impl EthernetHeader {
     }
 }
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct EventHeader {
-    pub subtype: u16,
-    pub length: u16,
-    pub version: u8,
-    pub oui: [u8; 3],
-    pub user_subtype: u16,
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct EventHeader {
+        pub subtype: u16,
+        pub length: u16,
+        pub version: u8,
+        pub oui: [u8; 3],
+        pub user_subtype: u16,
+    }
 }
 
 impl EventHeader {
@@ -228,34 +326,35 @@ impl EventHeader {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-// #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C, packed(2))]
-pub struct EventMessage {
-    /// version   
-    pub version: u16,
-    /// see flags below
-    pub flags: u16,
-    /// Message (see below)
-    pub event_type: u32,
-    /// Status code (see below)
-    pub status: u32,
-    /// Reason code (if applicable)
-    pub reason: u32,
-    /// WLC_E_AUTH
-    pub auth_type: u32,
-    /// data buf
-    pub datalen: u32,
-    /// Station address (if applicable)
-    pub addr: [u8; 6],
-    /// name of the incoming packet interface
-    pub ifname: [u8; 16],
-    /// destination OS i/f index
-    pub ifidx: u8,
-    /// source bsscfg index
-    pub bsscfgidx: u8,
-}
-impl_bytes!(EventMessage);
+wire_struct! {
+    #[derive(Debug, Clone, Copy)]
+    // #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C, packed(2))]
+    pub struct EventMessage {
+        /// version
+        pub version: u16,
+        /// see flags below
+        pub flags: u16,
+        /// Message (see below)
+        pub event_type: u32,
+        /// Status code (see below)
+        pub status: u32,
+        /// Reason code (if applicable)
+        pub reason: u32,
+        /// WLC_E_AUTH
+        pub auth_type: u32,
+        /// data buf
+        pub datalen: u32,
+        /// Station address (if applicable)
+        pub addr: [u8; 6],
+        /// name of the incoming packet interface
+        pub ifname: [u8; 16],
+        /// destination OS i/f index
+        pub ifidx: u8,
+        /// source bsscfg index
+        pub bsscfgidx: u8,
+    }
+}
 
 #[cfg(feature = "defmt")]
 impl defmt::Format for EventMessage {
@@ -308,30 +407,31 @@ impl EventMessage {
     }
 }
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C, packed(2))]
-pub struct EventPacket {
-    pub eth: EthernetHeader,
-    pub hdr: EventHeader,
-    pub msg: EventMessage,
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C, packed(2))]
+    pub struct EventPacket {
+        pub eth: EthernetHeader,
+        pub hdr: EventHeader,
+        pub msg: EventMessage,
+    }
 }
-impl_bytes!(EventPacket);
 
 impl EventPacket {
-    pub fn parse(packet: &mut [u8]) -> Option<(&mut Self, &mut [u8])> {
-        if packet.len() < Self::SIZE {
-            return None;
-        }
-
-        let (event_header, event_packet) = packet.split_at_mut(Self::SIZE);
-        let event_header = Self::from_bytes_mut(event_header.try_into().unwrap());
-        // warn!("event_header {:x}", event_header as *const _);
-        event_header.byteswap();
-
-        let event_packet = event_packet.get_mut(..event_header.msg.datalen as usize)?;
+    pub fn parse(packet: &mut [u8]) -> Result<(Self, &mut [u8]), ParseError> {
+        let mut header = Self::from_bytes(
+            packet
+                .get(..Self::SIZE)
+                .ok_or(ParseError::TooShort)?
+                .try_into()
+                .unwrap(),
+        );
+        header.byteswap();
 
-        Some((event_header, event_packet))
+        let len = header.msg.datalen as usize;
+        let payload = packet[Self::SIZE..].get_mut(..len).ok_or(ParseError::Truncated)?;
+        Ok((header, payload))
     }
 
     pub fn byteswap(&mut self) {
@@ -341,15 +441,16 @@ impl EventPacket {
     }
 }
 
-#[derive(Clone, Copy)]
-#[repr(C)]
-pub struct DownloadHeader {
-    pub flag: u16, //
-    pub dload_type: u16,
-    pub len: u32,
-    pub crc: u32,
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct DownloadHeader {
+        pub flag: u16, //
+        pub dload_type: u16,
+        pub len: u32,
+        pub crc: u32,
+    }
 }
-impl_bytes!(DownloadHeader);
 
 #[allow(unused)]
 pub const DOWNLOAD_FLAG_NO_CRC: u16 = 0x0001;
@@ -360,52 +461,102 @@ pub const DOWNLOAD_FLAG_HANDLER_VER: u16 = 0x1000;
 // Country Locale Matrix (CLM)
 pub const DOWNLOAD_TYPE_CLM: u16 = 2;
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct CountryInfo {
-    pub country_abbrev: [u8; 4],
-    pub rev: i32,
-    pub country_code: [u8; 4],
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct CountryInfo {
+        pub country_abbrev: [u8; 4],
+        pub rev: i32,
+        pub country_code: [u8; 4],
+    }
 }
-impl_bytes!(CountryInfo);
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct SsidInfo {
-    pub len: u32,
-    pub ssid: [u8; 32],
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SsidInfo {
+        pub len: u32,
+        pub ssid: [u8; 32],
+    }
 }
-impl_bytes!(SsidInfo);
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct PassphraseInfo {
-    pub len: u16,
-    pub flags: u16,
-    pub passphrase: [u8; 64],
+wire_struct! {
+    /// `wl_extjoin_params_t`, the parameter for the `join` iovar.
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct ExtJoinParams {
+        pub ssid: SsidInfo,
+        // wl_join_scan_params_t
+        pub scan_type: u8,
+        pub _pad: [u8; 3],
+        pub nprobes: u32,
+        pub active_time: u32,
+        pub passive_time: u32,
+        pub home_time: u32,
+        // wl_join_assoc_params_t
+        pub bssid: [u8; 6],
+        pub bssid_cnt: u16,
+        pub chanspec_num: u32,
+        pub chanspec_list: [u16; 1],
+        pub _pad2: [u8; 2],
+    }
 }
-impl_bytes!(PassphraseInfo);
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct SsidInfoWithIndex {
-    pub index: u32,
-    pub ssid_info: SsidInfo,
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct PassphraseInfo {
+        pub len: u16,
+        pub flags: u16,
+        pub passphrase: [u8; 64],
+    }
 }
-impl_bytes!(SsidInfoWithIndex);
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct EventMask {
-    pub iface: u32,
-    pub events: [u8; 24],
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SaePassphraseInfo {
+        pub len: u16,
+        pub passphrase: [u8; 128],
+    }
+}
+
+wire_struct! {
+    /// `scb_val_t`, for querying values about another station.
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct ScbVal {
+        pub val: u32,
+        pub addr: [u8; 6],
+        pub _pad: [u8; 2],
+    }
+}
+
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct SsidInfoWithIndex {
+        pub index: u32,
+        pub ssid_info: SsidInfo,
+    }
+}
+
+wire_struct! {
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct EventMask {
+        pub iface: u32,
+        pub events: [u8; 24],
+    }
 }
-impl_bytes!(EventMask);
 
 impl EventMask {
     pub fn unset(&mut self, evt: Event) {
@@ -414,83 +565,348 @@ impl EventMask {
     }
 }
 
-/// Parameters for a wifi scan
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C)]
-pub struct ScanParams {
-    pub version: u32,
-    pub action: u16,
-    pub sync_id: u16,
-    pub ssid_len: u32,
-    pub ssid: [u8; 32],
-    pub bssid: [u8; 6],
-    pub bss_type: u8,
-    pub scan_type: u8,
-    pub nprobes: u32,
-    pub active_time: u32,
-    pub passive_time: u32,
-    pub home_time: u32,
-    pub channel_num: u32,
-    pub channel_list: [u16; 1],
-}
-impl_bytes!(ScanParams);
-
-/// Wifi Scan Results Header, followed by `bss_count` `BssInfo`
-#[derive(Clone, Copy)]
-// #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C, packed(2))]
-pub struct ScanResults {
-    pub buflen: u32,
-    pub version: u32,
-    pub sync_id: u16,
-    pub bss_count: u16,
-}
-impl_bytes!(ScanResults);
+wire_struct! {
+    /// Parameters for a wifi scan
+    #[derive(Clone, Copy)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C)]
+    pub struct ScanParams {
+        pub version: u32,
+        pub action: u16,
+        pub sync_id: u16,
+        pub ssid_len: u32,
+        pub ssid: [u8; 32],
+        pub bssid: [u8; 6],
+        pub bss_type: u8,
+        pub scan_type: u8,
+        pub nprobes: u32,
+        pub active_time: u32,
+        pub passive_time: u32,
+        pub home_time: u32,
+        /// Number of valid entries in `channel_list` (low 16 bits).
+        pub channel_num: u32,
+        /// Chanspecs to scan. Empty means all channels.
+        pub channel_list: [u16; SCAN_MAX_CHANNELS],
+    }
+}
 
-impl ScanResults {
-    pub fn parse(packet: &mut [u8]) -> Option<(&mut ScanResults, &mut [u8])> {
-        if packet.len() < ScanResults::SIZE {
-            return None;
+impl ScanParams {
+    /// Passive scan of all channels, with the firmware default times.
+    pub fn new(action: u16) -> Self {
+        Self {
+            version: 1,
+            action,
+            sync_id: 1,
+            ssid_len: 0,
+            ssid: [0; 32],
+            bssid: [0xff; 6],
+            bss_type: 2,
+            scan_type: 1,
+            nprobes: !0,
+            active_time: !0,
+            passive_time: !0,
+            home_time: !0,
+            channel_num: 0,
+            channel_list: [0; SCAN_MAX_CHANNELS],
         }
+    }
+}
 
-        let (scan_results, bssinfo) = packet.split_at_mut(ScanResults::SIZE);
-        let scan_results = ScanResults::from_bytes_mut(scan_results.try_into().unwrap());
+/// Maximum number of channels in a scan channel list.
+pub const SCAN_MAX_CHANNELS: usize = 14;
+
+wire_struct! {
+    /// Wifi Scan Results Header, followed by `bss_count` `BssInfo`
+    #[derive(Clone, Copy)]
+    // #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C, packed(2))]
+    pub struct ScanResults {
+        pub buflen: u32,
+        pub version: u32,
+        pub sync_id: u16,
+        pub bss_count: u16,
+    }
+}
 
-        if scan_results.bss_count > 0 && bssinfo.len() < BssInfo::SIZE {
-            warn!("Scan result, incomplete BssInfo");
-            return None;
+impl ScanResults {
+    pub fn parse(packet: &mut [u8]) -> Result<(Self, &mut [u8]), ParseError> {
+        let header = Self::from_bytes(
+            packet
+                .get(..Self::SIZE)
+                .ok_or(ParseError::TooShort)?
+                .try_into()
+                .unwrap(),
+        );
+
+        let bss_info = &mut packet[Self::SIZE..];
+        if header.bss_count > 0 && bss_info.len() < BssInfo::SIZE {
+            return Err(ParseError::Truncated);
         }
 
-        Some((scan_results, bssinfo))
+        Ok((header, bss_info))
     }
 }
 
-/// Wifi Scan Result
-#[derive(Clone, Copy)]
-// #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(C, packed(2))]
-#[non_exhaustive]
-pub struct BssInfo {
-    pub version: u32,
-    pub length: u32,
-    pub bssid: [u8; 6],
-    pub beacon_period: u16,
-    pub capability: u16,
-    pub ssid_len: u8,
-    pub ssid: [u8; 32],
-    // there will be more stuff here
+wire_struct! {
+    /// Wifi Scan Result (`wl_bss_info_t`, version 109)
+    #[derive(Clone, Copy)]
+    // #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(C, packed(2))]
+    #[non_exhaustive]
+    pub struct BssInfo {
+        pub version: u32,
+        /// Length of this struct, including the IEs that follow it.
+        pub length: u32,
+        pub bssid: [u8; 6],
+        pub beacon_period: u16,
+        pub capability: u16,
+        pub ssid_len: u8,
+        pub ssid: [u8; 32],
+        pub(crate) _pad1: u8,
+        /// Number of valid entries in `rates`.
+        pub rateset_count: u32,
+        /// Supported rates, in units of 500kbps. The high bit marks basic rates.
+        pub rates: [u8; 16],
+        pub chanspec: u16,
+        pub atim_window: u16,
+        pub dtim_period: u8,
+        pub(crate) _pad2: u8,
+        /// Receive signal strength, in dBm.
+        pub rssi: i16,
+        /// Noise floor, in dBm.
+        pub phy_noise: i8,
+        /// 802.11n capable.
+        pub n_cap: u8,
+        pub(crate) _pad3: [u8; 2],
+        /// 802.11n capabilities.
+        pub nbss_cap: u32,
+        /// 802.11n control channel number.
+        pub ctl_ch: u8,
+        pub(crate) _pad4: [u8; 3],
+        pub(crate) _reserved32: u32,
+        pub flags: u8,
+        pub(crate) _reserved: [u8; 3],
+        /// 802.11n basic MCS set.
+        pub basic_mcs: [u8; 16],
+        /// Offset of the IEs from the start of this struct.
+        pub ie_offset: u16,
+        pub(crate) _pad5: [u8; 2],
+        /// Length of the IEs.
+        pub ie_length: u32,
+        pub snr: i16,
+        pub(crate) _pad6: [u8; 2],
+    }
 }
-impl_bytes!(BssInfo);
 
 impl BssInfo {
-    pub fn parse(packet: &mut [u8]) -> Option<&mut Self> {
-        if packet.len() < BssInfo::SIZE {
-            return None;
-        }
+    pub fn parse(packet: &[u8]) -> Result<Self, ParseError> {
+        let bytes = packet.get(..Self::SIZE).ok_or(ParseError::TooShort)?;
+        Ok(Self::from_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn sdpcm(header_length: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (SdpcmHeader::SIZE + payload.len()) as u16;
+        let header = SdpcmHeader {
+            len,
+            len_inv: !len,
+            sequence: 3,
+            channel_and_flags: 1,
+            next_length: 0,
+            header_length,
+            wireless_flow_control: 0,
+            bus_data_credit: 10,
+            reserved: [0; 2],
+        };
+        let mut packet = Vec::from(header.to_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn sdpcm_parse() {
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8, &[1, 2, 3]);
+        let (header, payload) = SdpcmHeader::parse(&mut packet).unwrap();
+        assert_eq!(header.sequence, 3);
+        assert_eq!(header.bus_data_credit, 10);
+        assert_eq!(payload, &[1, 2, 3]);
+
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8 + 2, &[1, 2, 3]);
+        let (_, payload) = SdpcmHeader::parse(&mut packet).unwrap();
+        assert_eq!(payload, &[3]);
+
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8 - 1, &[]);
+        assert_eq!(
+            SdpcmHeader::parse(&mut packet).err(),
+            Some(ParseError::InvalidHeaderLength)
+        );
+
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8 + 4, &[1, 2, 3]);
+        assert_eq!(SdpcmHeader::parse(&mut packet).err(), Some(ParseError::Truncated));
+
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8, &[1, 2, 3]);
+        packet.push(4);
+        assert_eq!(SdpcmHeader::parse(&mut packet).err(), Some(ParseError::LengthMismatch));
+
+        let mut packet = sdpcm(SdpcmHeader::SIZE as u8, &[]);
+        packet[2] ^= 1;
+        assert_eq!(SdpcmHeader::parse(&mut packet).err(), Some(ParseError::LengthMismatch));
+
+        let mut packet = [0; SdpcmHeader::SIZE - 1];
+        assert_eq!(SdpcmHeader::parse(&mut packet).err(), Some(ParseError::TooShort));
+    }
 
-        Some(BssInfo::from_bytes_mut(
-            packet[..BssInfo::SIZE].as_mut().try_into().unwrap(),
-        ))
+    fn cdc(len: u32, payload: &[u8]) -> Vec<u8> {
+        let header = CdcHeader {
+            cmd: 262,
+            len,
+            flags: 0x1234,
+            id: 0x1234,
+            status: 0,
+        };
+        let mut packet = Vec::from(header.to_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn cdc_parse() {
+        let mut packet = cdc(2, &[1, 2, 3]);
+        let (header, payload) = CdcHeader::parse(&mut packet).unwrap();
+        assert_eq!({ header.cmd }, 262);
+        assert_eq!({ header.id }, 0x1234);
+        assert_eq!(payload, &[1, 2]);
+
+        let mut packet = cdc(4, &[1, 2, 3]);
+        assert_eq!(CdcHeader::parse(&mut packet).err(), Some(ParseError::Truncated));
+
+        let mut packet = [0; CdcHeader::SIZE - 1];
+        assert_eq!(CdcHeader::parse(&mut packet).err(), Some(ParseError::TooShort));
+    }
+
+    #[test]
+    fn bdc_parse() {
+        let header = BdcHeader {
+            flags: BDC_VERSION << BDC_VERSION_SHIFT,
+            priority: 0,
+            flags2: 1,
+            data_offset: 1,
+        };
+        let mut packet = Vec::from(header.to_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 5, 6]);
+        let (header, payload) = BdcHeader::parse(&mut packet).unwrap();
+        assert_eq!(header.flags2 & BDC_FLAG2_IF_MASK, 1);
+        assert_eq!(payload, &[5, 6]);
+
+        packet.truncate(BdcHeader::SIZE + 3);
+        assert_eq!(BdcHeader::parse(&mut packet).err(), Some(ParseError::Truncated));
+
+        let mut packet = [0; BdcHeader::SIZE - 1];
+        assert_eq!(BdcHeader::parse(&mut packet).err(), Some(ParseError::TooShort));
+    }
+
+    fn event_packet(datalen: u32, payload: &[u8]) -> Vec<u8> {
+        let mut event = EventPacket::from_bytes(&[0; EventPacket::SIZE]);
+        event.eth.source_mac = [2, 0, 0, 0, 0, 1];
+        event.eth.ether_type = 0x886c;
+        event.hdr.subtype = 0x8001;
+        event.hdr.oui = [0x00, 0x10, 0x18];
+        event.hdr.user_subtype = 1;
+        event.msg.version = 2;
+        event.msg.event_type = 16;
+        event.msg.status = 0;
+        event.msg.reason = 3;
+        event.msg.datalen = datalen;
+        event.msg.ifidx = 1;
+        // The chip sends the event in network byte order.
+        event.byteswap();
+
+        let mut packet = Vec::from(event.to_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn event_packet_parse() {
+        let mut packet = event_packet(2, &[1, 2, 3]);
+        let (event, payload) = EventPacket::parse(&mut packet).unwrap();
+        assert_eq!({ event.eth.ether_type }, 0x886c);
+        assert_eq!({ event.hdr.subtype }, 0x8001);
+        assert_eq!({ event.hdr.user_subtype }, 1);
+        assert_eq!(event.hdr.oui, [0x00, 0x10, 0x18]);
+        assert_eq!({ event.msg.version }, 2);
+        assert_eq!({ event.msg.event_type }, 16);
+        assert_eq!({ event.msg.reason }, 3);
+        assert_eq!({ event.msg.datalen }, 2);
+        assert_eq!(event.msg.ifidx, 1);
+        assert_eq!(payload, &[1, 2]);
+
+        let mut packet = event_packet(4, &[1, 2, 3]);
+        assert_eq!(EventPacket::parse(&mut packet).err(), Some(ParseError::Truncated));
+
+        let mut packet = event_packet(0, &[]);
+        packet.pop();
+        assert_eq!(EventPacket::parse(&mut packet).err(), Some(ParseError::TooShort));
+    }
+
+    #[test]
+    fn scan_results_parse() {
+        let header = ScanResults {
+            buflen: 0,
+            version: 109,
+            sync_id: 1,
+            bss_count: 1,
+        };
+        let mut packet = Vec::from(header.to_bytes());
+        packet.extend_from_slice(&[0; BssInfo::SIZE]);
+        let (header, bss_info) = ScanResults::parse(&mut packet).unwrap();
+        assert_eq!({ header.bss_count }, 1);
+        assert_eq!(bss_info.len(), BssInfo::SIZE);
+
+        packet.pop();
+        assert_eq!(ScanResults::parse(&mut packet).err(), Some(ParseError::Truncated));
+
+        let mut packet = [0; ScanResults::SIZE - 1];
+        assert_eq!(ScanResults::parse(&mut packet).err(), Some(ParseError::TooShort));
+    }
+
+    #[test]
+    fn bss_info_round_trip() {
+        let mut info = BssInfo::from_bytes(&[0; BssInfo::SIZE]);
+        info.version = 109;
+        info.length = BssInfo::SIZE as u32;
+        info.bssid = [0, 0x11, 0x22, 0x33, 0x44, 0x55];
+        info.capability = 0x0411;
+        info.ssid_len = 4;
+        info.ssid[..4].copy_from_slice(b"test");
+        info.chanspec = 0x1006;
+        info.rssi = -42;
+        info.phy_noise = -91;
+        info.ctl_ch = 6;
+        info.ie_offset = BssInfo::SIZE as u16;
+        info.snr = 49;
+
+        let bytes = info.to_bytes();
+        let parsed = BssInfo::parse(&bytes).unwrap();
+        assert_eq!(parsed.bssid, info.bssid);
+        assert_eq!({ parsed.capability }, 0x0411);
+        assert_eq!(&parsed.ssid[..parsed.ssid_len as usize], b"test");
+        assert_eq!({ parsed.chanspec }, 0x1006);
+        assert_eq!({ parsed.rssi }, -42);
+        assert_eq!(parsed.phy_noise, -91);
+        assert_eq!(parsed.ctl_ch, 6);
+        assert_eq!({ parsed.snr }, 49);
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        assert_eq!(
+            BssInfo::parse(&bytes[..BssInfo::SIZE - 1]).err(),
+            Some(ParseError::TooShort)
+        );
     }
 }